@@ -0,0 +1,113 @@
+//! Masks as polygons and their export to YOLO segmentation txt files.
+
+use crate::error::{Error, Result};
+
+use image::GrayImage;
+
+use core::fmt;
+use std::{fs, path::Path};
+
+/// The outline of a mask as a closed polygon, normalized to the image once `normalize` is called.
+///
+/// It displays as `x1 y1 x2 y2 ...`, the point list of a YOLO segmentation line.
+#[derive(Clone)]
+pub struct Outline(Vec<[f32; 2]>);
+
+/// A mask candidate of a segmentation model, the mask has been turned into an outline.
+#[derive(Clone)]
+pub struct Candidate {
+    /// The outline of the mask.
+    pub outline: Outline,
+    /// The IoU predicted by the model, the confidence for detection masks.
+    pub iou: f32,
+    /// How much the mask changes with the threshold, 1 for masks that have no stability score.
+    pub stability: f32,
+    /// The position among the candidates of the model, to refine the selected one.
+    pub rank: usize,
+}
+
+impl Outline {
+    /// The longest contour of the mask after removing noise, None if the mask is empty.
+    pub fn from(mask: &GrayImage) -> Option<Self> {
+        crate::utils::extract_outline(mask).map(Outline)
+    }
+
+    /// Move every point, for masks that are a part of the image.
+    pub fn translate(mut self, offset: [f32; 2]) -> Self {
+        for point in &mut self.0 {
+            point[0] += offset[0];
+            point[1] += offset[1];
+        }
+
+        self
+    }
+
+    /// Divide the pixel coordinates by the image size.
+    pub fn normalize(mut self, img_size: [f32; 2]) -> Self {
+        for point in &mut self.0 {
+            point[0] /= img_size[0];
+            point[1] /= img_size[1];
+        }
+
+        self
+    }
+
+    /// The points of the polygon in order, normalized once `normalize` is called.
+    pub fn points(&self) -> &[[f32; 2]] {
+        &self.0
+    }
+}
+
+impl Candidate {
+    /// The normalized outline of a mask of an image of the given size, None if the mask is empty.
+    pub fn from_mask(
+        mask: &GrayImage,
+        iou: f32,
+        stability: f32,
+        img_size: [f32; 2],
+    ) -> Option<Self> {
+        let outline = Outline::from(mask)?;
+        Some(Self::from_outline(outline, iou, stability, img_size))
+    }
+
+    /// The same with an outline in pixels.
+    pub fn from_outline(outline: Outline, iou: f32, stability: f32, img_size: [f32; 2]) -> Self {
+        Candidate {
+            outline: outline.normalize(img_size),
+            iou,
+            stability,
+            rank: 0,
+        }
+    }
+}
+
+/// One line of a YOLO segmentation txt file: the class id and the normalized polygon.
+pub fn yolo_line(class_id: usize, outline: &Outline) -> String {
+    format!("{class_id} {outline}")
+}
+
+/// Write the lines of one image, the folder is created if needed.
+pub fn save_txt(path: &Path, lines: &[String]) -> Result<()> {
+    let write = || -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut content = lines.join("\n");
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        fs::write(path, content)
+    };
+
+    write().map_err(|e| Error::Export(path.to_path_buf(), e))
+}
+
+impl fmt::Display for Outline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut txt = String::new();
+        for [x, y] in &self.0 {
+            txt.push_str(&format!("{} {} ", x, y));
+        }
+        write!(f, "{}", txt)
+    }
+}
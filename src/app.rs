@@ -1,72 +1,95 @@
-mod model;
+//! The egui app, the models run on a worker thread and the ui sends it commands.
+
 mod threads;
 mod ui;
 
 use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::model;
+use std::path::PathBuf;
 use std::sync::mpsc;
-use threads::{Command, Return};
+use threads::{Command, Reply, Task};
 
+/// The labeling ui.
 pub struct App {
     config: Config,
+    folder: Option<PathBuf>, // opened when the ui starts
 }
 
 impl App {
+    /// Without a folder, images are opened from the menu.
     pub fn new(config: Config) -> Self {
-        App { config }
+        App {
+            config,
+            folder: None,
+        }
+    }
+
+    /// Open the images of a folder when the ui starts.
+    pub fn with_folder(mut self, folder: Option<PathBuf>) -> Self {
+        self.folder = folder;
+        self
     }
 
-    pub fn run(&self) {
-        let (task_sender, task_reciver) = mpsc::channel::<Command>();
-        let (result_sender, result_reciver) = mpsc::channel::<Return>();
+    /// Start the model worker and show the window until it is closed.
+    pub fn run(&self) -> Result<()> {
+        let (task_sender, task_reciver) = mpsc::channel::<Task>();
+        let (result_sender, result_reciver) = mpsc::channel::<Reply>();
 
-        threads::run(threads::ComputationData::new(
-            result_sender,
-            task_reciver,
-            &self.config.yolo_path,
-            &self.config.sam_e_path,
-            &self.config.sam_d_path,
-        ))
-        .expect("Create thread failed");
+        let data = threads::ComputationData::new(result_sender, task_reciver, &self.config)?;
+        let model_info = data.model_info();
+        threads::run(data)
+            .map_err(|e| Error::Ui(format!("failed to start the model thread: {e}")))?;
 
         // TODO: a copy here
-        ui::UiData::new(task_sender.clone(), result_reciver)
-            .run()
-            .expect("Run Ui Error");
-
-        task_sender
-            .send(threads::Command::End)
-            .expect("End Backend Thread Error");
+        let ran = ui::UiData::new(
+            task_sender.clone(),
+            result_reciver,
+            model_info,
+            &self.config,
+        )
+        .with_folder(self.folder.clone())
+        .run();
+
+        // the backend thread may have already stopped, nothing to do then
+        let _ = task_sender.send(Task {
+            job: 0,
+            generation: 0,
+            command: Command::End,
+        });
+
+        ran
     }
 }
 
 #[allow(unused)]
-pub fn test_sam() -> Result<(), Box<dyn std::error::Error>> {
+pub(crate) fn test_sam() -> std::result::Result<(), Box<dyn std::error::Error>> {
     use model::sam::prompt::Prompt;
 
-    let mut sam = model::sam::SAMmodel::new(); // load model
+    let mut sam = model::sam::SAMmodel::new()?; // load model
 
-    let img = image::open("tests/imgs/0000.jpg").unwrap();
+    let img = image::open("tests/imgs/0000.jpg")?;
 
     let prompt = Prompt::new_point(0.36, 0.39f32, 1.0f32);
 
-    let i = sam.forward(&img, prompt); // run forward
-    i.save("sam-result-test.png").unwrap();
+    let i = sam.forward(&img, prompt)?; // run forward
+    i.save("sam-result-test.png")?;
 
     Ok(())
 }
 
 #[allow(unused)]
-pub fn test_yolo() -> Result<(), Box<dyn std::error::Error>> {
-    let yolo = model::yolo::YOLOmodel::new(); // load model
+pub(crate) fn test_yolo() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut yolo = model::yolo::YOLOmodel::new()?; // load model
 
-    let img = image::open("tests/imgs/0000.jpg").unwrap();
+    let img = image::open("tests/imgs/0000.jpg")?;
 
-    let boxes = yolo.forward(&img); // run forward
+    let boxes = yolo.forward(&img, &Default::default())?; // run forward
 
     let mut last_conf = 1.0f32;
-    for (_bbox, conf) in &boxes {
-        assert!(*conf < last_conf);
-        last_conf = *conf;
+    for det in &boxes {
+        assert!(det.conf < last_conf);
+        last_conf = det.conf;
     }
 
     Ok(())
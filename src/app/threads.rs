@@ -1,135 +1,364 @@
-pub mod image_loader;
-
-use super::model::sam::prompt::Prompt;
-use super::ui::Outline;
+use super::ui::InstanceId;
+use crate::annotation::Candidate;
+use crate::config::{AutoMaskConfig, Config, DetectionConfig};
+use crate::error::{Error, Result};
+use crate::image_loader;
+use crate::model::sam::{prompt::Prompt, Embedding};
+use crate::model::yolo::Detection;
+use crate::model::ModelInfo;
 
 use std::{
+    collections::VecDeque,
     fmt,
     path::PathBuf,
-    sync::mpsc::{Receiver, Sender},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
     thread,
 };
 
+pub type JobId = u64;
+
+// a command and the job it belongs to,
+// the generation is the image the ui had when sending it and comes back with the result
+#[derive(Debug)]
+pub struct Task {
+    pub job: JobId,
+    pub generation: u64,
+    pub command: Command,
+}
+
+pub struct Reply {
+    pub job: JobId,
+    pub generation: u64,
+    pub ret: Return,
+}
+
 #[derive(Debug)]
 pub enum Command {
     ReadImage(PathBuf),
-    Segment(Vec<Vec<Prompt>>),
-    Detect,
+    Segment(Vec<(InstanceId, Vec<Prompt>, usize)>), // with the candidate selected before
+    Detect(DetectionConfig),
+    Redetect(DetectionConfig), // postprocess the last detection again with new thresholds
+    SegmentEverything(AutoMaskConfig),
+    Prefetch(Vec<PathBuf>), // images to load and embed when idle, replaces the previous ones
+    Cancel(JobId),          // drop a waiting job or stop a running one between instances
     End,
 }
 
 pub enum Return {
     Img(image_loader::Image),
-    Mask(Vec<Outline>),
-    BBox(Vec<[f32; 4]>),
+    // instance, the prompts used, mask candidates (empty if all the masks are empty)
+    Mask(Vec<(InstanceId, Vec<Prompt>, Vec<Candidate>)>),
+    // detections with the masks of segmentation models, whether to replace the previous ones
+    BBox(Vec<(Detection, Option<Candidate>)>, bool),
+    Everything(Vec<([f32; 2], Candidate)>), // the grid point and the mask of each object found
+    Error(Error),
+    Cancelled,
 
     Void,
 }
 
 pub struct ComputationData {
     img: Option<image_loader::Image>,
-    model: super::model::Models,
+    model: crate::model::Models,
+
+    prefetch: VecDeque<PathBuf>, // waiting to be loaded
+    prefetched: Vec<(image_loader::Image, Embedding)>, // ready for ReadImage
 
-    sender: Sender<Return>,
-    receiver: Receiver<Command>,
+    pending: VecDeque<Task>, // received while checking for cancellation
+    sender: Sender<Reply>,
+    receiver: Receiver<Task>,
 }
 
 // public
 impl ComputationData {
-    pub fn new(
-        sender: Sender<Return>,
-        receiver: Receiver<Command>,
-        yolo_path: &str,
-        sam_e_path: &str,
-        sam_d_path: &str,
-    ) -> Self {
-        ComputationData {
+    pub fn new(sender: Sender<Reply>, receiver: Receiver<Task>, config: &Config) -> Result<Self> {
+        Ok(ComputationData {
+            pending: VecDeque::new(),
             sender,
             receiver,
 
-            model: super::model::Models::new(yolo_path, sam_e_path, sam_d_path),
+            model: crate::model::Models::new(config)?,
             img: None,
-        }
+
+            prefetch: VecDeque::new(),
+            prefetched: Vec::new(),
+        })
+    }
+
+    pub fn model_info(&self) -> ModelInfo {
+        self.model.info()
     }
 }
 
-pub fn run(mut data: ComputationData) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO: Kill task
-    thread::spawn(move || {
-        while let Ok(task) = data.receiver.recv() {
-            match task {
-                Command::End => break,
-                _ => {
-                    let msg = task.to_string();
-                    data.run_task(task)
-                        .unwrap_or_else(|_| panic!("Failed to run task: {msg}"));
+pub fn run(mut data: ComputationData) -> std::io::Result<()> {
+    thread::Builder::new()
+        .name("computation".to_string())
+        .spawn(move || {
+            while let Some(task) = data.next_task() {
+                let ret = match task.command {
+                    Command::End => break,
+                    Command::Prefetch(paths) => {
+                        data.set_prefetch(paths);
+                        Ok(())
+                    }
+                    Command::Cancel(job) => data.cancel_pending(job),
+                    // the cancel of a job sent after it is only seen by draining the channel
+                    _ if data.is_cancelled(task.job) => data.reply(&task, Return::Cancelled),
+                    _ => data.run_task(task),
+                };
+                // the ui is gone, nobody is waiting for the result
+                if ret.is_err() {
+                    break;
                 }
             }
-        }
-    });
+        })?;
     Ok(())
 }
 
 // private
 impl ComputationData {
-    fn run_task(&mut self, task: Command) -> Result<(), Box<dyn std::error::Error>> {
+    // prefetch while waiting for a command, None if the ui is gone
+    fn next_task(&mut self) -> Option<Task> {
+        if let Some(task) = self.pending.pop_front() {
+            return Some(task);
+        }
+
+        loop {
+            match self.receiver.try_recv() {
+                Ok(task) => return Some(task),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => {
+                    if !self.prefetch_one() {
+                        return self.receiver.recv().ok();
+                    }
+                }
+            }
+        }
+    }
+
+    // keep what is still wanted, drop the rest
+    fn set_prefetch(&mut self, paths: Vec<PathBuf>) {
+        self.prefetched.retain(|(img, _)| paths.contains(&img.path));
+        self.prefetch = paths
+            .into_iter()
+            .filter(|path| !self.prefetched.iter().any(|(img, _)| img.path == *path))
+            .collect();
+    }
+
+    // returns false if there is nothing to prefetch
+    fn prefetch_one(&mut self) -> bool {
+        let Some(path) = self.prefetch.pop_front() else {
+            return false;
+        };
+
+        let timer = std::time::Instant::now();
+        let prefetched = image_loader::Image::load(path.clone())
+            .and_then(|img| Ok((self.model.encode(&img.data)?, img)));
+        match prefetched {
+            Ok((embedding, img)) => self.prefetched.push((img, embedding)),
+            // the error shows up again when the image is opened
+            Err(e) => println!("Failed to prefetch {}: {e}", path.display()),
+        }
+        Self::time(timer, "Prefetch");
+
+        true
+    }
+
+    // a job drained into self.pending while another one ran is answered as cancelled right away
+    fn cancel_pending(&mut self, job: JobId) -> std::result::Result<(), mpsc::SendError<Reply>> {
+        match self.pending.iter().position(|task| task.job == job) {
+            Some(i) => {
+                let task = self.pending.remove(i).unwrap();
+                self.reply(&task, Return::Cancelled)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // called by a running job between steps, the other commands wait in self.pending
+    fn is_cancelled(&mut self, job: JobId) -> bool {
+        Self::check_cancel(&self.receiver, &mut self.pending, job)
+    }
+
+    // the same as is_cancelled, for when the model is borrowed
+    fn check_cancel(receiver: &Receiver<Task>, pending: &mut VecDeque<Task>, job: JobId) -> bool {
+        loop {
+            match receiver.try_recv() {
+                Ok(task) => pending.push_back(task),
+                Err(TryRecvError::Empty) => break,
+                // the ui is gone, nobody is waiting for the result
+                Err(TryRecvError::Disconnected) => return true,
+            }
+        }
+
+        let cancel = pending
+            .iter()
+            .position(|task| matches!(task.command, Command::Cancel(j) if j == job));
+        match cancel {
+            Some(i) => {
+                pending.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // only fails if the result can not be sent back
+    fn run_task(&mut self, task: Task) -> std::result::Result<(), mpsc::SendError<Reply>> {
         let timer = std::time::Instant::now();
-        let msg = task.to_string();
-        let ret = match task {
-            Command::ReadImage(path) => self.read_image(path),
-            Command::Segment(s) => self.segment(s),
-            Command::Detect => self.detect(),
-            Command::End => Return::Void,
+        let msg = task.command.to_string();
+        let ret = match &task.command {
+            Command::ReadImage(path) => self.read_image(path.clone()),
+            Command::Segment(s) => self.segment(task.job, s),
+            Command::Detect(params) => self.detect(task.job, *params),
+            Command::Redetect(params) => self.redetect(*params),
+            Command::SegmentEverything(config) => self.segment_everything(task.job, config),
+            Command::Prefetch(_) | Command::Cancel(_) | Command::End => Ok(Return::Void),
         };
         Self::time(timer, &msg);
-        self.sender.send(ret).expect("Failed to send Return");
-        Ok(())
+        self.reply(&task, ret.unwrap_or_else(Return::Error))
     }
 
-    fn read_image(&mut self, path: PathBuf) -> Return {
-        let img = image_loader::Image::load(path).unwrap();
+    fn reply(&self, task: &Task, ret: Return) -> std::result::Result<(), mpsc::SendError<Reply>> {
+        self.sender.send(Reply {
+            job: task.job,
+            generation: task.generation,
+            ret,
+        })
+    }
 
-        self.img = Some(img);
-        self.model.embed(&self.img.as_ref().unwrap().data);
+    fn read_image(&mut self, path: PathBuf) -> Result<Return> {
+        self.img = None;
 
-        Return::Img(self.img.clone().unwrap()) // TODO: clone happends here
+        if let Some(i) = self.prefetched.iter().position(|(img, _)| img.path == path) {
+            let (img, embedding) = self.prefetched.remove(i);
+            self.model.set_embedding(embedding);
+            self.img = Some(img.clone());
+            return Ok(Return::Img(img));
+        }
+
+        let img = image_loader::Image::load(path)?;
+        self.model.embed(&img.data)?;
+        self.img = Some(img.clone()); // TODO: clone happends here
+
+        Ok(Return::Img(img))
     }
 
-    fn segment(&mut self, instances_prompts: Vec<Vec<Prompt>>) -> Return {
-        match &self.img {
-            Some(img) => {
-                let mut outlines = Vec::new();
+    fn segment(
+        &mut self,
+        job: JobId,
+        instances_prompts: &[(InstanceId, Vec<Prompt>, usize)],
+    ) -> Result<Return> {
+        let size = match &self.img {
+            Some(img) => img.size,
+            None => return Err(Error::Inference("No image to segment".into())),
+        };
 
-                for prompts in instances_prompts {
-                    let mask = self.model.generate_mask(prompts).to_luma8();
-                    outlines.push(Outline::from(&mask).normalize(img.size));
-                }
+        let mut masks = Vec::new();
+        for chunk in instances_prompts.chunks(self.model.batch_size()) {
+            if self.is_cancelled(job) {
+                return Ok(Return::Cancelled);
+            }
 
-                Return::Mask(outlines)
+            for (_, prompts, selected) in chunk {
+                self.model.select_candidate(prompts, *selected);
             }
-            None => {
-                println!("No image to segment");
-                Return::Void
+            let batch = chunk
+                .iter()
+                .map(|(_, prompts, _)| prompts.clone())
+                .collect();
+            let results = self.model.generate_masks(batch)?;
+            for ((id, prompts, _), candidates) in chunk.iter().zip(results) {
+                // empty masks are dropped, the rank keeps the index of the model
+                let candidates = candidates
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(rank, c)| {
+                        let candidate =
+                            Candidate::from_mask(&c.mask.to_luma8(), c.iou, c.stability, size)?;
+                        Some(Candidate { rank, ..candidate })
+                    })
+                    .collect();
+                masks.push((*id, prompts.clone(), candidates));
             }
         }
+
+        Ok(Return::Mask(masks))
     }
 
-    fn detect(&mut self) -> Return {
-        let img_ref = self.img.as_ref();
+    fn segment_everything(&mut self, job: JobId, config: &AutoMaskConfig) -> Result<Return> {
+        let img = match &self.img {
+            Some(img) => img,
+            None => return Err(Error::Inference("No image to segment".into())),
+        };
 
-        match img_ref {
-            Some(img_ref) => {
-                // the points for boxes have already been normalized
-                let boxes = self.model.detect(&img_ref.data);
+        let (receiver, pending) = (&self.receiver, &mut self.pending);
+        let mut cancelled = || Self::check_cancel(receiver, pending, job);
+        let Some(masks) = self
+            .model
+            .segment_everything(&img.data, config, &mut cancelled)?
+        else {
+            return Ok(Return::Cancelled);
+        };
 
-                let mut prompts = Vec::new();
-                for (bb, _) in boxes.iter() {
-                    prompts.push(bb.into());
-                }
-                Return::BBox(prompts)
-            }
-            None => Return::Void,
+        let found = masks
+            .into_iter()
+            .filter_map(|m| {
+                let c = m.candidate;
+                let candidate =
+                    Candidate::from_mask(&c.mask.to_luma8(), c.iou, c.stability, img.size)?;
+                Some((m.point, candidate))
+            })
+            .collect();
+
+        Ok(Return::Everything(found))
+    }
+
+    fn detect(&mut self, job: JobId, params: DetectionConfig) -> Result<Return> {
+        let Some(img) = &self.img else {
+            return Err(Error::Inference("No image to detect".into()));
+        };
+        let size = img.size;
+
+        self.model.infer(&img.data)?;
+        // the postprocess decodes the masks of segmentation models
+        if self.is_cancelled(job) {
+            return Ok(Return::Cancelled);
         }
+        // the points for boxes have already been normalized
+        let detections = self.model.redetect(&params)?;
+        Ok(Return::BBox(Self::detection_masks(detections, size), false))
+    }
+
+    fn redetect(&mut self, params: DetectionConfig) -> Result<Return> {
+        let size = match &self.img {
+            Some(img) => img.size,
+            None => return Err(Error::Inference("No image to detect".into())),
+        };
+
+        let detections = self.model.redetect(&params)?;
+        Ok(Return::BBox(Self::detection_masks(detections, size), true))
+    }
+
+    // turn the masks of a segmentation model into outlines, the confidence is used as the iou
+    fn detection_masks(
+        detections: Vec<Detection>,
+        size: [f32; 2],
+    ) -> Vec<(Detection, Option<Candidate>)> {
+        detections
+            .into_iter()
+            .map(|mut det| {
+                let candidate = det.mask.take().and_then(|mask| {
+                    Some(Candidate::from_outline(
+                        mask.outline()?,
+                        det.conf,
+                        1.0,
+                        size,
+                    ))
+                });
+                (det, candidate)
+            })
+            .collect()
     }
 }
 
@@ -144,9 +373,142 @@ impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Command::ReadImage(_) => write!(f, "Read Image"),
-            Command::Detect => write!(f, "Detect"),
+            Command::Detect(_) => write!(f, "Detect"),
+            Command::Redetect(_) => write!(f, "Re-detect"),
             Command::Segment(_) => write!(f, "Segment"),
+            Command::SegmentEverything(_) => write!(f, "Segment Everything"),
+            Command::Prefetch(_) => write!(f, "Prefetch"),
+            Command::Cancel(_) => write!(f, "Cancel"),
             Command::End => write!(f, "End"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // the queued commands are waiting before the worker starts
+    fn mock_worker(queued: Vec<(JobId, Command)>) -> (Sender<Task>, Receiver<Reply>) {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "yolo_path": "", "sam_e_path": "", "sam_d_path": "",
+                "detector": "mock", "segmenter": "mock"
+            }"#,
+        )
+        .unwrap();
+
+        let (task_sender, task_receiver) = mpsc::channel::<Task>();
+        let (reply_sender, reply_receiver) = mpsc::channel::<Reply>();
+        for (job, command) in queued {
+            task_sender
+                .send(Task {
+                    job,
+                    generation: 0,
+                    command,
+                })
+                .unwrap();
+        }
+        let data = ComputationData::new(reply_sender, task_receiver, &config).unwrap();
+        run(data).unwrap();
+
+        (task_sender, reply_receiver)
+    }
+
+    fn request(
+        sender: &Sender<Task>,
+        receiver: &Receiver<Reply>,
+        job: JobId,
+        command: Command,
+    ) -> Return {
+        sender
+            .send(Task {
+                job,
+                generation: 0,
+                command,
+            })
+            .unwrap();
+        let reply = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(reply.job, job);
+        reply.ret
+    }
+
+    #[test]
+    fn worker_with_mock_models() {
+        let path = std::env::temp_dir().join(format!("label-sam-mock-{}.png", std::process::id()));
+        image::RgbImage::new(64, 48).save(&path).unwrap();
+        let (sender, receiver) = mock_worker(Vec::new());
+
+        let ret = request(&sender, &receiver, 1, Command::ReadImage(path.clone()));
+        assert!(matches!(ret, Return::Img(img) if img.size == [64.0, 48.0]));
+
+        let ret = request(&sender, &receiver, 2, Command::Detect(Default::default()));
+        let Return::BBox(detections, false) = ret else {
+            panic!("Detect did not return boxes");
+        };
+        assert_eq!(detections.len(), 2);
+        assert!(detections.iter().all(|(_, mask)| mask.is_none()));
+
+        let prompts = vec![Prompt::new_box(0.25, 0.25, 0.75, 0.75)];
+        let ret = request(
+            &sender,
+            &receiver,
+            3,
+            Command::Segment(vec![(7, prompts, 0)]),
+        );
+        let Return::Mask(masks) = ret else {
+            panic!("Segment did not return masks");
+        };
+        assert_eq!(masks.len(), 1);
+        assert_eq!(masks[0].0, 7);
+        assert_eq!(masks[0].2.len(), 1);
+
+        let ret = request(
+            &sender,
+            &receiver,
+            4,
+            Command::SegmentEverything(Default::default()),
+        );
+        assert!(matches!(ret, Return::Everything(found) if found.len() == 4));
+
+        sender
+            .send(Task {
+                job: 0,
+                generation: 0,
+                command: Command::End,
+            })
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn queued_jobs_can_be_cancelled() {
+        let path =
+            std::env::temp_dir().join(format!("label-sam-cancel-{}.png", std::process::id()));
+        image::RgbImage::new(64, 48).save(&path).unwrap();
+        // the cancel comes after both jobs, as the ui sends it,
+        // reading an image has no cancellation point of its own
+        let (sender, receiver) = mock_worker(vec![
+            (1, Command::ReadImage(path.clone())),
+            (2, Command::ReadImage(path.clone())),
+            (3, Command::Cancel(2)),
+        ]);
+
+        let reply = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(reply.job, 1);
+        assert!(matches!(reply.ret, Return::Img(_)));
+        let reply = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(reply.job, 2);
+        assert!(matches!(reply.ret, Return::Cancelled));
+
+        sender
+            .send(Task {
+                job: 0,
+                generation: 0,
+                command: Command::End,
+            })
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,63 +1,63 @@
+mod classes;
+mod history;
 mod instance;
 mod state;
 
-use super::threads::{Command, Return};
+use super::threads::{Command, JobId, Reply, Return, Task};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::model::ModelInfo;
 use imageproc::drawing::Canvas;
-pub use instance::Outline;
-use state::{OptMode, PromptHover, PromptType, UiState};
+pub use instance::InstanceId;
+use state::{OptMode, PointLabel, PromptHover, PromptType, UiState};
 
 use egui::{
-    CentralPanel, ColorImage, Painter, Rect, Sense, SidePanel, TextureOptions, TopBottomPanel,
+    CentralPanel, ColorImage, Modifiers, Painter, Rect, RichText, Sense, SidePanel, TextureOptions,
+    TopBottomPanel,
 };
 use strum::IntoEnumIterator;
 
+use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
 
 pub struct UiData {
-    sender: Sender<Command>,
-    receiver: Receiver<Return>,
+    sender: Sender<Task>,
+    receiver: Receiver<Reply>,
 
     state: UiState,
     running: bool, // when a task is running, disable the buttons
+
+    next_job: JobId,
+    jobs: Vec<JobId>, // sent and waiting for the result
+    generation: u64,  // changes with the image, results of older ones are dropped
 }
 
 impl eframe::App for UiData {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.handle_hotkeys(ctx);
+
         self.draw_info_column(ctx);
 
         self.draw_button_row(ctx);
 
+        self.draw_status_row(ctx);
+
         self.draw_img_area(ctx);
 
-        // handle return values
-        if let Ok(ret) = self.receiver.try_recv() {
-            match ret {
-                Return::Img(img) => {
-                    let crate::app::threads::image_loader::Image {
-                        data,
-                        path,
-                        size,
-                        file_size,
-                    } = img;
-                    self.state.img = Some(data);
-                    self.state.img_ori_size = Some(size);
-                    self.state.img_path = Some(path);
-                    self.state.img_file_size = Some(file_size);
-
-                    self.running = false;
-                }
-                Return::Mask(ins_masks) => {
-                    self.running = false;
+        self.draw_diagnostics(ctx);
 
-                    for (i, mask) in ins_masks.into_iter().enumerate() {
-                        self.state.instances[i].add_mask(mask);
-                    }
-                }
-                Return::BBox(boxes) => {
-                    self.state.add_yolo_boxes(boxes);
-                    self.running = false;
-                }
-                Return::Void => self.running = false,
+        // handle return values
+        if let Ok(Reply {
+            job,
+            generation,
+            ret,
+        }) = self.receiver.try_recv()
+        {
+            self.jobs.retain(|&j| j != job);
+            if generation != self.generation {
+                println!("Dropped the result of job {job} for a previous image");
+            } else {
+                self.handle_return(ret);
             }
         }
 
@@ -67,17 +67,40 @@ impl eframe::App for UiData {
 
 // private
 impl UiData {
-    pub fn new(sender: Sender<Command>, receiver: Receiver<Return>) -> Self {
+    pub fn new(
+        sender: Sender<Task>,
+        receiver: Receiver<Reply>,
+        model_info: ModelInfo,
+        config: &Config,
+    ) -> Self {
         UiData {
             sender,
             receiver,
 
-            state: UiState::new(),
+            state: UiState::new(model_info, config),
             running: false,
+
+            next_job: 0,
+            jobs: Vec::new(),
+            generation: 0,
         }
     }
 
-    pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    // open the images of a folder from the start, the first one is loaded
+    pub fn with_folder(mut self, folder: Option<PathBuf>) -> Self {
+        if let Some(folder) = folder {
+            self.state.set_folder(&folder);
+            if self.state.file_paths.is_empty() {
+                self.state.message = Some(format!("No images in {}", folder.display()));
+            } else {
+                self.next_img();
+            }
+        }
+
+        self
+    }
+
+    pub fn run(self) -> Result<()> {
         eframe::run_native(
             "Label-SAM",
             eframe::NativeOptions {
@@ -90,8 +113,7 @@ impl UiData {
             },
             Box::new(move |_cc| Ok(Box::new(self))),
         )
-        .unwrap();
-        Ok(())
+        .map_err(|e| Error::Ui(e.to_string()))
     }
 }
 
@@ -105,6 +127,14 @@ impl UiData {
 
             self.draw_instance_info(ui);
 
+            ui.separator();
+
+            self.draw_class_info(ui);
+
+            ui.separator();
+
+            self.draw_provider_info(ui);
+
             // TODO
             // Prompt Section
             // TODO
@@ -122,9 +152,9 @@ impl UiData {
                         self.open_folder();
                     }
                     if ui.button("Save").clicked() {
-                        self.state
-                            .save_mask()
-                            .unwrap_or_else(|e| println!("Error: {}", e));
+                        if let Err(e) = self.state.save_mask() {
+                            self.state.message = Some(e.to_string());
+                        }
                     }
                     if ui.button("Next Image").clicked() {
                         self.next_img();
@@ -135,15 +165,16 @@ impl UiData {
                     if ui.button("Detect").clicked() {
                         self.detect();
                     }
+                    if ui.button("Cancel").clicked() {
+                        self.cancel();
+                    }
 
                     ui.separator();
                     if ui.button("Undo").clicked() {
-                        // TODO:
-                        println!("Undo");
+                        self.state.undo();
                     }
                     if ui.button("Redo").clicked() {
-                        // TODO:
-                        println!("Redo");
+                        self.state.redo();
                     }
                 });
 
@@ -154,6 +185,13 @@ impl UiData {
                         ui.radio_value(&mut self.state.prompt_type, variant, variant.to_string());
                     }
 
+                    // label of point prompt, shift or right click for negative
+                    ui.separator();
+                    ui.label("Point: ");
+                    for variant in PointLabel::iter() {
+                        ui.radio_value(&mut self.state.point_label, variant, variant.to_string());
+                    }
+
                     ui.separator();
 
                     // selection for prompt hover
@@ -163,8 +201,45 @@ impl UiData {
                     }
                 });
 
+                ui.horizontal(|ui| {
+                    // thresholds for detection, re-detect applies them without running yolo again
+                    let params = &mut self.state.detection;
+                    ui.label("Confidence: ");
+                    ui.add(egui::Slider::new(&mut params.conf_threshold, 0.0..=1.0));
+                    ui.label("IoU: ");
+                    ui.add(egui::Slider::new(&mut params.iou_threshold, 0.0..=1.0));
+                    ui.label("Max Detections: ");
+                    ui.add(egui::DragValue::new(&mut params.max_detections).range(0..=1000));
+                    ui.checkbox(&mut params.sam_refine, "Refine with SAM");
+
+                    if ui.button("Re-detect").clicked() {
+                        self.redetect();
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    // segment everything with a grid of points, for objects yolo does not know
+                    let params = &mut self.state.auto;
+                    ui.label("Grid Points: ");
+                    ui.add(egui::DragValue::new(&mut params.points_per_side).range(1..=64));
+                    ui.label("Predicted IoU: ");
+                    ui.add(egui::Slider::new(&mut params.iou_threshold, 0.0..=1.0));
+                    ui.label("Stability: ");
+                    ui.add(egui::Slider::new(
+                        &mut params.stability_threshold,
+                        0.0..=1.0,
+                    ));
+                    ui.label("Crop Layers: ");
+                    ui.add(egui::DragValue::new(&mut params.crop_layers).range(0..=2));
+
+                    if ui.button("Segment Everything").clicked() {
+                        self.segment_everything();
+                    }
+                });
+
                 ui.horizontal(|ui| {
                     ui.checkbox(&mut self.state.selection_mode, "Selection Mode");
+                    ui.checkbox(&mut self.state.auto_segment, "Auto Segment");
 
                     // operation mode: add new instance or add to existing instance or delete instance
                     ui.separator();
@@ -180,6 +255,22 @@ impl UiData {
         });
     }
 
+    fn draw_status_row(&mut self, ctx: &egui::Context) {
+        TopBottomPanel::bottom("Status Area").show(ctx, |ui| {
+            ui.horizontal(|ui| match &self.state.message {
+                Some(msg) => {
+                    ui.label(RichText::new(msg).color(egui::Color32::RED));
+                    if ui.button("Dismiss").clicked() {
+                        self.state.message = None;
+                    }
+                }
+                None => {
+                    ui.label("Ready");
+                }
+            });
+        });
+    }
+
     fn draw_img_area(&mut self, ctx: &egui::Context) {
         // acquire the mouse position
         let mouse_pos = ctx
@@ -239,18 +330,61 @@ impl UiData {
             }
 
             let mut to_remove = Vec::new();
+            let mut class_changes = Vec::new();
+            let mut next_masks = Vec::new();
+            let classes = &self.state.classes;
             for (i, b) in self.state.selection.iter_mut().enumerate() {
+                let ins = &self.state.instances[i];
+                let mut label = format!("Instance {}", i);
+                if let Some(conf) = ins.conf {
+                    label.push_str(&format!(" {:.2}", conf));
+                }
+
                 ui.horizontal(|ui| {
-                    ui.checkbox(b, format!("Instance {}", i));
+                    ui.checkbox(b, label);
+                    egui::ComboBox::from_id_salt(("instance class", i))
+                        .selected_text(classes.name(ins.class_id))
+                        .show_ui(ui, |ui| {
+                            for (class_id, class) in classes.classes.iter().enumerate() {
+                                let selected = ins.class_id == class_id;
+                                if ui.selectable_label(selected, &class.name).clicked() {
+                                    class_changes.push((i, class_id));
+                                }
+                            }
+                        });
                     if ui.button("Delete").clicked() {
                         to_remove.push(i);
                     }
                 });
+                // the candidates from SAM, the best one is used by default
+                if ins.masks.len() > 1 {
+                    ui.horizontal(|ui| {
+                        if let Some(c) = ins.masks.get() {
+                            ui.label(format!(
+                                "Mask {}/{} IoU {:.2} Stability {:.2}",
+                                ins.masks.selected + 1,
+                                ins.masks.len(),
+                                c.iou,
+                                c.stability
+                            ));
+                        }
+                        if ui.button("Next Mask").clicked() {
+                            next_masks.push(i);
+                        }
+                    });
+                }
                 if !(*b) {
                     self.state.select_all = false;
                 }
             }
 
+            for (i, class_id) in class_changes {
+                self.state.change_class(i, class_id);
+            }
+            for i in next_masks {
+                self.state.next_mask(i);
+            }
+
             to_remove.reverse();
             for i in to_remove {
                 self.state.remove_instance(i);
@@ -278,10 +412,130 @@ impl UiData {
             });
         });
     }
+
+    fn draw_class_info(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Classes (1-9 to set the class)");
+
+            let state = &mut self.state;
+            for (i, class) in state.classes.classes.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut state.current_class, i, format!("{}", i));
+                    ui.color_edit_button_srgba(&mut class.color);
+                    ui.text_edit_singleline(&mut class.name);
+                });
+            }
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut state.new_class_name);
+                if ui.button("Add Class").clicked() && !state.new_class_name.is_empty() {
+                    let name = std::mem::take(&mut state.new_class_name);
+                    state.classes.add(name);
+                }
+            });
+
+            if ui.button("Save Classes").clicked() {
+                self.save_classes();
+            }
+        });
+    }
+
+    fn draw_provider_info(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.label("Execution Providers");
+            for (model, provider) in &self.state.providers {
+                ui.label(format!("{}: {}", model, provider));
+                // the configured providers that could not load the model
+                for (failed, reason) in &provider.failed {
+                    let text = RichText::new(format!("  {failed} failed: {reason}")).weak();
+                    ui.add(egui::Label::new(text).truncate())
+                        .on_hover_text(reason);
+                }
+            }
+            ui.checkbox(&mut self.state.show_diagnostics, "Model Diagnostics");
+        });
+    }
+
+    // the inputs, outputs and metadata of the loaded models
+    fn draw_diagnostics(&mut self, ctx: &egui::Context) {
+        let state = &mut self.state;
+        egui::Window::new("Model Diagnostics")
+            .open(&mut state.show_diagnostics)
+            .vscroll(true)
+            .show(ctx, |ui| {
+                if state.signatures.is_empty() {
+                    ui.label("No model files are loaded");
+                }
+                for (i, signature) in state.signatures.iter().enumerate() {
+                    egui::CollapsingHeader::new(signature.model)
+                        .id_salt(i)
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            ui.label(format!("Path: {}", signature.path));
+                            for (title, values) in [
+                                ("Inputs", &signature.inputs),
+                                ("Outputs", &signature.outputs),
+                                ("Metadata", &signature.metadata),
+                            ] {
+                                if values.is_empty() {
+                                    continue;
+                                }
+                                ui.label(RichText::new(title).strong());
+                                for (name, value) in values {
+                                    ui.label(format!("  {name}: {value}"));
+                                }
+                            }
+                        });
+                }
+            });
+    }
 }
 
 // private, backend thread related
 impl UiData {
+    // the result of a job for the current image
+    fn handle_return(&mut self, ret: Return) {
+        match ret {
+            Return::Img(img) => {
+                let crate::image_loader::Image {
+                    data,
+                    path,
+                    size,
+                    file_size,
+                } = img;
+                self.state.img = Some(data);
+                self.state.img_ori_size = Some(size);
+                self.state.img_path = Some(path);
+                self.state.img_file_size = Some(file_size);
+
+                self.running = false;
+            }
+            Return::Mask(ins_masks) => {
+                self.running = false;
+
+                self.state.add_masks(ins_masks);
+            }
+            Return::Everything(masks) => {
+                self.state.add_auto_masks(masks);
+                self.running = false;
+            }
+            Return::BBox(boxes, replace) => {
+                self.state.add_yolo_boxes(boxes, replace);
+                self.running = false;
+
+                if self.state.detection.sam_refine {
+                    self.segment();
+                }
+            }
+            Return::Error(e) => {
+                self.state.message = Some(e.to_string());
+                self.running = false;
+            }
+            Return::Cancelled => self.running = false,
+            Return::Void => self.running = false,
+        }
+    }
+
     fn open_folder(&mut self) {
         if self.running {
             println!("task running, try again later");
@@ -292,33 +546,71 @@ impl UiData {
 
         self.state.open_folder();
         println!("Target Folder Set.");
+        self.prefetch();
         self.running = false;
     }
 
+    // whatever is running is for the previous image and is cancelled, so Next never waits
     fn next_img(&mut self) {
-        if self.running {
-            println!("task running, try again later");
-            return;
-        } else {
-            self.running = true;
-        }
-
         let file = self.state.next_img();
         if let Some(path) = file {
-            self.sender
-                .send(Command::ReadImage(path))
-                .expect("Failed to send command ReadImage");
+            self.cancel();
+            self.generation += 1;
+            // the results of the cancelled jobs are dropped, the image clears it
+            self.running = true;
+            self.send(Command::ReadImage(path));
+            self.prefetch();
         } else {
             println!("Loading cancelled");
         }
-        self.running = false;
     }
 
+    // load the next images ahead, the ones that are not next anymore are dropped
+    fn prefetch(&mut self) {
+        self.send(Command::Prefetch(self.state.prefetch_paths()));
+    }
+
+    // only the changed instances are sent
     fn segment(&mut self) {
         let instances_prompts = self.state.format_prompts();
-        self.sender
-            .send(Command::Segment(instances_prompts))
-            .expect("Failed to send command Segment");
+        if instances_prompts.is_empty() {
+            return;
+        }
+        self.send(Command::Segment(instances_prompts));
+    }
+
+    // cancel every job that has not returned yet
+    fn cancel(&mut self) {
+        for job in self.jobs.clone() {
+            self.send(Command::Cancel(job));
+        }
+    }
+
+    // every command is a new job for the current image
+    fn send(&mut self, command: Command) {
+        let job = self.next_job;
+        self.next_job += 1;
+
+        // prefetch and cancel have no result
+        let has_result = !matches!(command, Command::Prefetch(_) | Command::Cancel(_));
+        let msg = command.to_string();
+        let sent = self.sender.send(Task {
+            job,
+            generation: self.generation,
+            command,
+        });
+
+        match sent {
+            Ok(()) if has_result => self.jobs.push(job),
+            Ok(()) => (),
+            // the model thread is gone, nothing will come back
+            Err(_) => {
+                self.running = false;
+                self.state.message = Some(format!(
+                    "Failed to send {msg}, the model thread has stopped, restart the app"
+                ));
+            }
+        }
     }
 
     fn detect(&mut self) {
@@ -329,19 +621,58 @@ impl UiData {
             self.running = true;
         }
 
-        self.sender
-            .send(Command::Detect)
-            .expect("Failed to send command Detect");
+        self.send(Command::Detect(self.state.detection));
+    }
+
+    fn segment_everything(&mut self) {
+        if self.running {
+            println!("task running, try again later");
+            return;
+        } else {
+            self.running = true;
+        }
+
+        self.send(Command::SegmentEverything(self.state.auto));
+    }
+
+    fn redetect(&mut self) {
+        if self.running {
+            println!("task running, try again later");
+            return;
+        } else {
+            self.running = true;
+        }
+
+        self.send(Command::Redetect(self.state.detection));
     }
 
     fn input_on_img(&mut self, response: egui::Response, mouse_pos: egui::Vec2) {
+        // a click deletes the last prompt of the selected instance, whatever the prompt type
+        if self.state.operation_mode == OptMode::Delete {
+            if response.clicked() {
+                self.state.remove_prompt();
+                if self.state.auto_segment {
+                    self.segment();
+                }
+            }
+            return;
+        }
+
         // handle input
         match self.state.prompt_type {
             PromptType::None => (),
             PromptType::Point => {
-                if response.clicked() {
+                if response.secondary_clicked() {
+                    let p = self.normalize(mouse_pos.into());
+                    self.img_pointed(p, PointLabel::Negative);
+                } else if response.clicked() {
                     let p = self.normalize(mouse_pos.into());
-                    self.img_pointed(p);
+                    let label = if response.ctx.input(|i| i.modifiers.shift) {
+                        PointLabel::Negative
+                    } else {
+                        self.state.point_label
+                    };
+                    self.img_pointed(p, label);
                 }
             }
             PromptType::Box => {
@@ -372,19 +703,79 @@ impl UiData {
     }
 
     // the input has been normalized
-    fn img_pointed(&mut self, point: [f32; 2]) {
-        self.state.pointed(point);
+    fn img_pointed(&mut self, point: [f32; 2], label: PointLabel) {
+        self.state.pointed(point, label);
+        if self.state.auto_segment {
+            self.segment();
+        }
     }
 
     // the input has been normalized
     fn img_boxed(&mut self, bbox: [f32; 4]) {
         // TODO: selection mode
         self.state.boxed(bbox, true);
+        if self.state.auto_segment {
+            self.segment();
+        }
     }
 }
 
 // private, utils
 impl UiData {
+    // number keys set the class, 1 for the first class
+    fn handle_hotkeys(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+
+        // check the one with shift first, otherwise ctrl+z also matches it
+        let (redo, undo) = ctx.input_mut(|i| {
+            let redo = i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, egui::Key::Z);
+            let undo = i.consume_key(Modifiers::COMMAND, egui::Key::Z);
+            (redo, undo)
+        });
+        if redo {
+            self.state.redo();
+        } else if undo {
+            self.state.undo();
+        }
+
+        const KEYS: [egui::Key; 9] = [
+            egui::Key::Num1,
+            egui::Key::Num2,
+            egui::Key::Num3,
+            egui::Key::Num4,
+            egui::Key::Num5,
+            egui::Key::Num6,
+            egui::Key::Num7,
+            egui::Key::Num8,
+            egui::Key::Num9,
+        ];
+        let pressed = ctx.input(|i| KEYS.iter().position(|k| i.key_pressed(*k)));
+        if let Some(class_id) = pressed {
+            if class_id < self.state.classes.len() {
+                self.state.set_class(class_id);
+            }
+        }
+    }
+
+    fn save_classes(&mut self) {
+        let path = match &self.state.classes.path {
+            Some(path) => Some(path.clone()),
+            None => rfd::FileDialog::new()
+                .set_title("Save Classes")
+                .set_file_name("classes.txt")
+                .save_file(),
+        };
+
+        if let Some(path) = path {
+            match self.state.classes.save(&path) {
+                Ok(()) => self.state.classes.path = Some(path),
+                Err(e) => self.state.message = Some(e.to_string()),
+            }
+        }
+    }
+
     fn normalize(&self, point: [f32; 2]) -> [f32; 2] {
         let size = self.state.img.as_ref().unwrap().dimensions();
         let [isx, isy] = [size.0 as f32, size.1 as f32];
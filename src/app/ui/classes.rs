@@ -0,0 +1,145 @@
+use crate::error::{Error, Result};
+
+use egui::Color32;
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+// colors for classes without one, the same order as the ultralytics palette
+const PALETTE: [[u8; 3]; 20] = [
+    [255, 56, 56],
+    [255, 157, 151],
+    [255, 112, 31],
+    [255, 178, 29],
+    [207, 210, 49],
+    [72, 249, 10],
+    [146, 204, 23],
+    [61, 219, 134],
+    [26, 147, 52],
+    [0, 212, 187],
+    [44, 153, 168],
+    [0, 194, 255],
+    [52, 69, 147],
+    [100, 115, 255],
+    [0, 24, 236],
+    [132, 56, 255],
+    [82, 0, 133],
+    [203, 56, 255],
+    [255, 149, 200],
+    [255, 55, 199],
+];
+
+#[derive(Clone)]
+pub struct LabelClass {
+    pub name: String,
+    pub color: Color32,
+}
+
+// the classes of the project, the index is the class id in the export
+pub struct ClassList {
+    pub classes: Vec<LabelClass>,
+    pub path: Option<PathBuf>, // where the list is loaded from and saved to
+}
+
+impl ClassList {
+    pub fn from_names(names: &[String]) -> Self {
+        let classes = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| LabelClass {
+                name: name.clone(),
+                color: Self::palette(i),
+            })
+            .collect();
+
+        ClassList {
+            classes,
+            path: None,
+        }
+    }
+
+    // one class per line, optionally followed by a color like "person #ff3838"
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("failed to read {}: {e}", path.display())))?;
+
+        let classes = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| match line.rsplit_once(' ') {
+                Some((name, color)) if Self::parse_color(color).is_some() => LabelClass {
+                    name: name.trim().to_string(),
+                    color: Self::parse_color(color).unwrap(),
+                },
+                _ => LabelClass {
+                    name: line.to_string(),
+                    color: Self::palette(i),
+                },
+            })
+            .collect();
+
+        Ok(ClassList {
+            classes,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let write = || -> std::io::Result<()> {
+            let mut f = File::create(path)?;
+            for class in &self.classes {
+                let [r, g, b, _] = class.color.to_array();
+                writeln!(f, "{} #{:02x}{:02x}{:02x}", class.name, r, g, b)?;
+            }
+            Ok(())
+        };
+
+        write().map_err(|e| Error::Export(path.to_path_buf(), e))
+    }
+
+    pub fn add(&mut self, name: String) {
+        let color = Self::palette(self.classes.len());
+        self.classes.push(LabelClass { name, color });
+    }
+
+    pub fn len(&self) -> usize {
+        self.classes.len()
+    }
+
+    // detections may use a class id beyond the list
+    pub fn name(&self, class_id: usize) -> String {
+        match self.classes.get(class_id) {
+            Some(class) => class.name.clone(),
+            None => format!("class {}", class_id),
+        }
+    }
+
+    pub fn color(&self, class_id: usize) -> Color32 {
+        match self.classes.get(class_id) {
+            Some(class) => class.color,
+            None => Self::palette(class_id),
+        }
+    }
+}
+
+// utils
+impl ClassList {
+    fn palette(i: usize) -> Color32 {
+        let [r, g, b] = PALETTE[i % PALETTE.len()];
+        Color32::from_rgb(r, g, b)
+    }
+
+    fn parse_color(s: &str) -> Option<Color32> {
+        let hex = s.strip_prefix('#')?;
+        if hex.len() != 6 {
+            return None;
+        }
+        let v = u32::from_str_radix(hex, 16).ok()?;
+        Some(Color32::from_rgb((v >> 16) as u8, (v >> 8) as u8, v as u8))
+    }
+}
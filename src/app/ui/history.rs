@@ -0,0 +1,258 @@
+use super::instance::{Instance, Masks};
+use super::state::UiState;
+use crate::model::sam::prompt::Prompt;
+
+// the number of edits kept for one image
+const HISTORY_LIMIT: usize = 100;
+
+// a reversible change to the instances of the current image
+#[derive(Clone)]
+pub enum Edit {
+    AddInstance(usize, Instance, bool), // index, instance, selected
+    RemoveInstance(usize, Instance, bool),
+    AddPrompt(usize, Prompt, bool), // index of instance, prompt, is manual (only for box)
+    RemovePrompt(usize, Prompt, bool), // the last prompt of the instance
+    SetMasks(usize, Masks, Masks),  // index of instance, old, new
+    SetClass(usize, usize, usize),  // index of instance, old, new
+    Batch(Vec<Edit>),               // applied in order, reverted in reverse order
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>,
+}
+
+impl Edit {
+    pub fn apply(&self, state: &mut UiState) {
+        match self {
+            Edit::AddInstance(idx, ins, selected) => {
+                state.instances.insert(*idx, ins.clone());
+                state.selection.insert(*idx, *selected);
+            }
+            Edit::RemoveInstance(idx, _, _) => {
+                state.instances.remove(*idx);
+                state.selection.remove(*idx);
+            }
+            Edit::AddPrompt(idx, prompt, is_manual) => {
+                state.instances[*idx].push_prompt(*prompt, *is_manual);
+            }
+            Edit::RemovePrompt(idx, _, _) => state.instances[*idx].pop_prompt(),
+            Edit::SetMasks(idx, _, new) => {
+                state.instances[*idx].set_masks(new.clone());
+                state.instances[*idx].edits += 1;
+            }
+            Edit::SetClass(idx, _, new) => {
+                state.instances[*idx].class_id = *new;
+                state.instances[*idx].edits += 1;
+            }
+            Edit::Batch(edits) => {
+                for edit in edits {
+                    edit.apply(state);
+                }
+            }
+        }
+    }
+
+    pub fn revert(&self, state: &mut UiState) {
+        match self {
+            Edit::AddInstance(idx, _, _) => {
+                state.instances.remove(*idx);
+                state.selection.remove(*idx);
+            }
+            Edit::RemoveInstance(idx, ins, selected) => {
+                state.instances.insert(*idx, ins.clone());
+                state.selection.insert(*idx, *selected);
+            }
+            Edit::AddPrompt(idx, _, _) => state.instances[*idx].pop_prompt(),
+            Edit::RemovePrompt(idx, prompt, is_manual) => {
+                state.instances[*idx].push_prompt(*prompt, *is_manual);
+            }
+            Edit::SetMasks(idx, old, _) => {
+                state.instances[*idx].set_masks(old.clone());
+                state.instances[*idx].edits -= 1;
+            }
+            Edit::SetClass(idx, old, _) => {
+                state.instances[*idx].class_id = *old;
+                state.instances[*idx].edits -= 1;
+            }
+            Edit::Batch(edits) => {
+                for edit in edits.iter().rev() {
+                    edit.revert(state);
+                }
+            }
+        }
+    }
+}
+
+impl History {
+    pub fn push(&mut self, edit: Edit) {
+        self.undo.push(edit);
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        let edit = self.undo.pop()?;
+        self.redo.push(edit.clone());
+        Some(edit)
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        let edit = self.redo.pop()?;
+        self.undo.push(edit.clone());
+        Some(edit)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::Candidate;
+    use crate::config::Config;
+    use crate::model::ModelInfo;
+
+    fn state() -> UiState {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "yolo_path": "", "sam_e_path": "", "sam_d_path": "",
+                "detector": "mock", "segmenter": "mock"
+            }"#,
+        )
+        .unwrap();
+        let info = ModelInfo {
+            providers: Vec::new(),
+            class_names: Vec::new(),
+            signatures: Vec::new(),
+        };
+        UiState::new(info, &config)
+    }
+
+    fn candidate() -> Candidate {
+        let mut mask = image::GrayImage::new(80, 40);
+        for (x, y, p) in mask.enumerate_pixels_mut() {
+            if (20..60).contains(&x) && (10..30).contains(&y) {
+                p.0[0] = 255;
+            }
+        }
+        Candidate::from_mask(&mask, 0.9, 1.0, [80.0, 40.0]).unwrap()
+    }
+
+    #[test]
+    fn edits_are_undone_and_redone() {
+        let mut state = state();
+        let point = Prompt::new_point(0.2, 0.2, 1.0);
+        let negative = Prompt::new_point(0.4, 0.4, 0.0);
+        let masks = Masks::new(vec![candidate()], vec![point, negative]);
+
+        state.perform(Edit::AddInstance(
+            0,
+            Instance::new_point(0.2, 0.2, 1.0),
+            true,
+        ));
+        state.perform(Edit::AddPrompt(0, negative, false));
+        state.perform(Edit::SetMasks(0, Masks::default(), masks));
+        assert_eq!(state.instances[0].prompts.len(), 2);
+        assert_eq!(state.instances[0].masks.len(), 1);
+        assert_eq!(state.instances[0].edits, 1);
+
+        state.undo();
+        assert_eq!(state.instances[0].masks.len(), 0);
+        assert_eq!(state.instances[0].edits, 0);
+        state.undo();
+        assert_eq!(state.instances[0].prompts.len(), 1);
+        state.undo();
+        assert!(state.instances.is_empty());
+        assert!(state.selection.is_empty());
+        // nothing left to undo
+        state.undo();
+
+        state.redo();
+        state.redo();
+        state.redo();
+        assert_eq!(state.instances[0].prompts, vec![point, negative]);
+        assert_eq!(state.instances[0].masks.len(), 1);
+        assert!(!state.instances[0].is_dirty());
+
+        // the last prompt comes back with the undo of its removal
+        state.perform(Edit::RemovePrompt(0, negative, false));
+        assert_eq!(state.instances[0].prompts, vec![point]);
+        state.undo();
+        assert_eq!(state.instances[0].prompts, vec![point, negative]);
+    }
+
+    #[test]
+    fn batches_are_reverted_in_reverse_order() {
+        let mut state = state();
+        state.perform(Edit::Batch(vec![
+            Edit::AddInstance(0, Instance::new_point(0.2, 0.2, 1.0), true),
+            Edit::AddPrompt(0, Prompt::new_point(0.3, 0.3, 0.0), false),
+            Edit::SetClass(0, 0, 2),
+        ]));
+        assert_eq!(state.instances[0].class_id, 2);
+
+        state.undo();
+        assert!(state.instances.is_empty());
+        state.redo();
+        assert_eq!(state.instances[0].prompts.len(), 2);
+        assert_eq!(state.instances[0].class_id, 2);
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut state = state();
+        state.perform(Edit::AddInstance(
+            0,
+            Instance::new_point(0.2, 0.2, 1.0),
+            true,
+        ));
+        state.undo();
+        state.perform(Edit::AddInstance(
+            0,
+            Instance::new_point(0.6, 0.6, 1.0),
+            true,
+        ));
+
+        state.redo();
+        assert_eq!(state.instances.len(), 1);
+        assert!(state.history.pop_redo().is_none());
+    }
+
+    #[test]
+    fn the_oldest_edit_is_dropped_at_the_limit() {
+        let mut history = History::default();
+        for class in 0..HISTORY_LIMIT + 1 {
+            history.push(Edit::SetClass(0, class, class + 1));
+        }
+        assert_eq!(history.undo.len(), HISTORY_LIMIT);
+        assert!(matches!(history.undo[0], Edit::SetClass(0, 1, 2)));
+
+        let mut undone = 0;
+        while history.pop_undo().is_some() {
+            undone += 1;
+        }
+        assert_eq!(undone, HISTORY_LIMIT);
+    }
+
+    #[test]
+    fn history_is_reset_with_the_image() {
+        let mut state = state();
+        state.perform(Edit::AddInstance(
+            0,
+            Instance::new_point(0.2, 0.2, 1.0),
+            true,
+        ));
+        state.reset_instance();
+
+        state.undo();
+        state.redo();
+        assert!(state.instances.is_empty());
+    }
+}
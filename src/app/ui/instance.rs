@@ -1,35 +1,58 @@
-use core::fmt;
-
 use super::state::PromptHover;
-use crate::app::model::sam::prompt::Prompt;
+use crate::annotation::{self, Candidate, Outline};
+use crate::model::sam::prompt::Prompt;
+use crate::model::yolo::Detection;
 
-use image::GrayImage;
+// given by UiState, stays the same when other instances are added or removed
+pub type InstanceId = u64;
 
+#[derive(Clone)]
 pub struct Instance {
-    pub mask: Option<Outline>,
+    pub id: InstanceId,
+    pub masks: Masks,
     pub prompts: Vec<Prompt>,
 
     pub box_manual: Vec<bool>,
 
     pub pos: Option<[f32; 2]>,
+
+    pub class_id: usize,
+    pub conf: Option<f32>, // only for instances from detection
+    pub edits: usize,      // masks and classes set since it was added, undo takes them back
 }
 
-#[derive(Clone)]
-pub struct Outline(Vec<[f32; 2]>);
+// the candidates are sorted by score, the first one is used by default
+#[derive(Clone, Default)]
+pub struct Masks {
+    pub candidates: Vec<Candidate>,
+    pub selected: usize,
+    pub prompts: Vec<Prompt>, // the prompts the masks are made from
+}
 
-impl Outline {
-    pub fn from(mask: &GrayImage) -> Self {
-        let outline = crate::utils::extract_outline(mask);
-        Outline(outline)
+impl Masks {
+    pub fn new(candidates: Vec<Candidate>, prompts: Vec<Prompt>) -> Self {
+        Self {
+            candidates,
+            selected: 0,
+            prompts,
+        }
     }
 
-    pub fn normalize(mut self, img_size: [f32; 2]) -> Self {
-        for point in &mut self.0 {
-            point[0] /= img_size[0];
-            point[1] /= img_size[1];
-        }
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
 
-        self
+    pub fn get(&self) -> Option<&Candidate> {
+        self.candidates.get(self.selected)
+    }
+
+    // the same candidates with the next one selected
+    pub fn next(&self) -> Self {
+        Self {
+            candidates: self.candidates.clone(),
+            selected: (self.selected + 1) % self.len().max(1),
+            prompts: self.prompts.clone(),
+        }
     }
 }
 
@@ -39,10 +62,14 @@ impl Instance {
         let prompts = vec![Prompt::new_point(x, y, label)];
 
         Self {
-            mask: None,
+            id: 0,
+            masks: Masks::default(),
             prompts,
             box_manual: Vec::new(),
             pos: Some([x, y]),
+            class_id: 0,
+            conf: None,
+            edits: 0,
         }
     }
 
@@ -51,40 +78,56 @@ impl Instance {
         let box_manual = vec![is_manual];
 
         Self {
-            mask: None,
+            id: 0,
+            masks: Masks::default(),
             prompts,
             box_manual,
             pos: Some([(bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0]),
+            class_id: 0,
+            conf: None,
+            edits: 0,
         }
     }
 
-    pub fn add_point_label(&mut self, x: f32, y: f32, label: f32) {
-        self.prompts.push(Prompt::new_point(x, y, label));
-        self.update_pos();
+    pub fn new_detection(det: Detection) -> Self {
+        Self {
+            class_id: det.class_id,
+            conf: Some(det.conf),
+            ..Self::new_box(det.bbox.into(), false)
+        }
     }
 
-    pub fn add_box(&mut self, bbox: [f32; 4], is_manual: bool) {
-        self.prompts
-            .push(Prompt::new_box(bbox[0], bbox[1], bbox[2], bbox[3]));
-        self.box_manual.push(is_manual);
+    // is_manual is only used by box
+    pub fn push_prompt(&mut self, prompt: Prompt, is_manual: bool) {
+        if let Prompt::Box(_) = prompt {
+            self.box_manual.push(is_manual);
+        }
+        self.prompts.push(prompt);
         self.update_pos();
     }
 
-    pub fn add_mask(&mut self, mask: Outline) {
-        match &self.mask {
-            Some(_) => (),
-            None => {
-                self.mask = Some(mask);
-            }
+    pub fn pop_prompt(&mut self) {
+        if let Some(Prompt::Box(_)) = self.prompts.pop() {
+            self.box_manual.pop();
         }
         self.update_pos();
     }
 
+    pub fn set_masks(&mut self, masks: Masks) {
+        self.masks = masks;
+        self.update_pos();
+    }
+
+    // the outline of the selected candidate
+    pub fn mask(&self) -> Option<&Outline> {
+        self.masks.get().map(|c| &c.outline)
+    }
+
     fn update_pos(&mut self) {
         let mut pos = [0.0f32, 0.0];
         let mut count = 0;
-        if let Some(mask) = &self.mask {
-            for point in &mask.0 {
+        if let Some(mask) = self.mask() {
+            for point in mask.points() {
                 pos[0] += point[0];
                 pos[1] += point[1];
                 count += 1;
@@ -113,6 +156,17 @@ impl Instance {
         }
     }
 
+    // the prompts have changed since the masks were made
+    pub fn is_dirty(&self) -> bool {
+        self.masks.prompts != self.prompts
+    }
+
+    // whether the instance only has the box from detection and nobody has segmented it,
+    // picked another mask or changed its class, re-detecting can replace it
+    pub fn is_detection(&self) -> bool {
+        self.prompts.len() == 1 && self.box_manual == [false] && self.edits == 0
+    }
+
     // return the distance between the given pos and self.pos
     pub fn get_distance(&self, pos: [f32; 2]) -> f32 {
         match self.pos {
@@ -126,16 +180,8 @@ impl Instance {
     }
 
     pub fn format_txt(&self) -> Option<String> {
-        match &self.mask {
-            None => None,
-            Some(outline) => {
-                let mut txt = String::new();
-                txt.push_str("0 ");
-                txt.push_str(&outline.to_string());
-
-                Some(txt)
-            }
-        }
+        self.mask()
+            .map(|outline| annotation::yolo_line(self.class_id, outline))
     }
 }
 
@@ -196,19 +242,23 @@ impl Instance {
         }
     }
 
-    pub fn draw_outline(&self, painter: &egui::Painter, img_size: &[f32; 2], img_pos: &[f32; 2]) {
-        if let Some(mask) = &self.mask {
-            for i in 0..mask.0.len() {
-                let p1 = &mask.0[i];
-                let p2 = &mask.0[(i + 1) % mask.0.len()];
+    pub fn draw_outline(
+        &self,
+        painter: &egui::Painter,
+        img_size: &[f32; 2],
+        img_pos: &[f32; 2],
+        color: egui::Color32,
+    ) {
+        if let Some(mask) = self.mask() {
+            let points = mask.points();
+            for i in 0..points.len() {
+                let p1 = &points[i];
+                let p2 = &points[(i + 1) % points.len()];
                 let p1 = Self::denormalize(*p1, *img_size, *img_pos);
                 let p2 = Self::denormalize(*p2, *img_size, *img_pos);
 
                 painter.circle_filled(p1.into(), 1.0, egui::Color32::LIGHT_YELLOW);
-                painter.line_segment(
-                    [p1.into(), p2.into()],
-                    egui::Stroke::new(1.0, egui::Color32::RED),
-                );
+                painter.line_segment([p1.into(), p2.into()], egui::Stroke::new(1.0, color));
             }
         }
     }
@@ -220,13 +270,3 @@ impl Instance {
         [p[0] * scale[0] + delta[0], p[1] * scale[1] + delta[1]]
     }
 }
-
-impl fmt::Display for Outline {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut txt = String::new();
-        for [x, y] in &self.0 {
-            txt.push_str(&format!("{} {} ", x, y));
-        }
-        write!(f, "{}", txt)
-    }
-}
@@ -1,11 +1,19 @@
-use crate::app::model::sam::prompt::Prompt;
-
-use super::instance::Instance;
+use crate::annotation::{self, Candidate};
+use crate::config::{AutoMaskConfig, Config, DetectionConfig};
+use crate::error::Result;
+use crate::model::sam::prompt::Prompt;
+use crate::model::signature::Signature;
+use crate::model::yolo::Detection;
+use crate::model::{ModelInfo, SessionProvider};
+
+use super::classes::ClassList;
+use super::history::{Edit, History};
+use super::instance::{Instance, InstanceId, Masks};
 
 use image::DynamicImage;
 
 use core::fmt;
-use std::{fs::File, io::Write, path::PathBuf};
+use std::path::PathBuf;
 
 pub struct UiState {
     pub img_label: String,
@@ -15,22 +23,40 @@ pub struct UiState {
     pub img_ori_size: Option<[f32; 2]>,
     pub img_file_size: Option<f32>,
     pub img_path: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>, // where the save dialog starts
 
     pub prompt_type: PromptType,
+    pub point_label: PointLabel, // for left click, right click always adds a negative point
     pub prompt_hover: PromptHover,
     pub operation_mode: OptMode,
 
     pub selection_mode: bool,
+    pub auto_segment: bool, // segment right after each prompt
+
+    pub detection: DetectionConfig,
+    pub auto: AutoMaskConfig,
+
+    pub classes: ClassList,
+    pub current_class: usize, // the class for new instances
+    pub new_class_name: String,
 
     pub instances: Vec<Instance>,
     pub selection: Vec<bool>,
     pub select_all: bool,
+    pub history: History, // edits of the instances of the current image
+    pub next_id: InstanceId,
 
     pub drag_start: [f32; 2],
     pub drag_end: [f32; 2],
 
     pub file_paths: Vec<PathBuf>,
     pub file_index: Option<usize>,
+    pub prefetch: usize, // the number of next images to load ahead
+
+    pub providers: Vec<(&'static str, SessionProvider)>,
+    pub signatures: Vec<Signature>, // of the loaded models, for the diagnostics window
+    pub show_diagnostics: bool,
+    pub message: Option<String>, // the last error to show in the ui
 }
 
 #[derive(PartialEq, strum_macros::EnumIter, Copy, Clone)]
@@ -40,6 +66,12 @@ pub enum PromptType {
     Box,
 }
 
+#[derive(PartialEq, strum_macros::EnumIter, Copy, Clone)]
+pub enum PointLabel {
+    Positive, // foreground
+    Negative, // background
+}
+
 #[derive(PartialEq, strum_macros::EnumIter, Copy, Clone)]
 pub enum PromptHover {
     None,
@@ -58,37 +90,148 @@ pub enum OptMode {
 
 // Data related
 impl UiState {
-    pub fn new() -> Self {
+    pub fn new(model_info: ModelInfo, config: &Config) -> Self {
+        // the class file comes first, then the names from config or the model
+        let mut message = None;
+        let classes = match &config.classes_path {
+            Some(path) => ClassList::load(path.as_ref()).unwrap_or_else(|e| {
+                message = Some(format!("{e}, using the class names of the model"));
+                ClassList::from_names(&model_info.class_names)
+            }),
+            None => ClassList::from_names(&model_info.class_names),
+        };
+
         UiState {
             img_label: "Load image first".to_string(),
             img_pos: None,
 
             img: None,
             img_path: None,
+            output_dir: config.output_dir.as_ref().map(PathBuf::from),
             img_ori_size: None,
             img_file_size: None,
 
             prompt_type: PromptType::None,
+            point_label: PointLabel::Positive,
             prompt_hover: PromptHover::All,
             operation_mode: OptMode::None,
 
             selection_mode: false,
+            auto_segment: false,
+
+            detection: config.detection,
+            auto: config.auto,
+
+            classes,
+            current_class: 0,
+            new_class_name: String::new(),
 
             instances: Vec::new(),
             selection: Vec::new(),
             select_all: true,
+            history: History::default(),
+            next_id: 0,
 
             drag_start: [-100.0, -100.0],
             drag_end: [-100.0, -100.0],
 
             file_paths: Vec::new(),
+            prefetch: config.cache.prefetch,
             file_index: None,
+
+            providers: model_info.providers,
+            signatures: model_info.signatures,
+            show_diagnostics: false,
+            message,
         }
     }
 
-    pub fn add_yolo_boxes(&mut self, boxes: Vec<[f32; 4]>) {
-        for bbox in boxes {
-            self.boxed(bbox, false);
+    // every mask found by segment everything becomes an instance of the current class,
+    // its grid point is the prompt
+    pub fn add_auto_masks(&mut self, masks: Vec<([f32; 2], Candidate)>) {
+        let mut edits = Vec::new();
+        for (i, (point, candidate)) in masks.into_iter().enumerate() {
+            let mut instance = Instance::new_point(point[0], point[1], 1.0);
+            instance.id = self.new_id();
+            instance.class_id = self.current_class;
+            instance.set_masks(Masks::new(vec![candidate], instance.prompts.clone()));
+            edits.push(Edit::AddInstance(
+                self.instances.len() + i,
+                instance,
+                self.select_all,
+            ));
+        }
+
+        self.perform(Edit::Batch(edits));
+    }
+
+    // add the detections as instances in one edit,
+    // with replace, the instances that only have a box from yolo and no edits are removed first
+    // the masks of a segmentation model are used as they are, unless they are refined by SAM
+    pub fn add_yolo_boxes(
+        &mut self,
+        detections: Vec<(Detection, Option<Candidate>)>,
+        replace: bool,
+    ) {
+        let mut edits = Vec::new();
+
+        let mut len = self.instances.len();
+        if replace {
+            for i in (0..self.instances.len()).rev() {
+                if self.instances[i].is_detection() {
+                    edits.push(self.remove_edit(i));
+                    len -= 1;
+                }
+            }
+        }
+
+        // a flat box can not be a prompt
+        let detections = detections
+            .into_iter()
+            .filter(|(det, _)| det.bbox.has_area());
+        for (i, (det, candidate)) in detections.enumerate() {
+            let mut instance = Instance::new_detection(det);
+            instance.id = self.new_id();
+            if let Some(candidate) = candidate {
+                // without the prompts, the mask is dirty and segmented again
+                let prompts = if self.detection.sam_refine {
+                    Vec::new()
+                } else {
+                    instance.prompts.clone()
+                };
+                instance.masks = Masks::new(vec![candidate], prompts);
+            }
+            edits.push(Edit::AddInstance(len + i, instance, self.select_all));
+        }
+
+        self.perform(Edit::Batch(edits));
+    }
+
+    // candidates are empty for an empty mask, the new masks replace the old ones
+    // the instances removed since sending are skipped
+    pub fn add_masks(&mut self, masks: Vec<(InstanceId, Vec<Prompt>, Vec<Candidate>)>) {
+        let mut edits = Vec::new();
+        for (id, prompts, candidates) in masks {
+            let Some(i) = self.instances.iter().position(|ins| ins.id == id) else {
+                continue;
+            };
+            if candidates.is_empty() {
+                self.message = Some(format!("Instance {i} got an empty mask"));
+            }
+            // an empty mask is kept too, so the same prompts are not sent again
+            let old = self.instances[i].masks.clone();
+            edits.push(Edit::SetMasks(i, old, Masks::new(candidates, prompts)));
+        }
+
+        self.perform(Edit::Batch(edits));
+    }
+
+    // use the next mask candidate of the instance
+    pub fn next_mask(&mut self, idx: usize) {
+        let masks = &self.instances[idx].masks;
+        if masks.len() > 1 {
+            let edit = Edit::SetMasks(idx, masks.clone(), masks.next());
+            self.perform(edit);
         }
     }
 
@@ -145,9 +288,47 @@ impl UiState {
         self.select_all = false;
     }
 
+    // change the class of the only selected instance, and use it for new instances
+    pub fn set_class(&mut self, class_id: usize) {
+        self.current_class = class_id;
+
+        let selected = self.check_selection();
+        if selected >= 0 {
+            self.change_class(selected as usize, class_id);
+        }
+    }
+
+    pub fn change_class(&mut self, idx: usize, class_id: usize) {
+        let old = self.instances[idx].class_id;
+        if old != class_id {
+            self.perform(Edit::SetClass(idx, old, class_id));
+        }
+    }
+
     pub fn remove_instance(&mut self, idx: usize) {
-        self.instances.remove(idx);
-        self.selection.remove(idx);
+        let edit = self.remove_edit(idx);
+        self.perform(edit);
+    }
+
+    // the last prompt of the selected instance, an instance without prompts is removed
+    pub fn remove_prompt(&mut self) {
+        let selected = self.check_selection();
+        if selected < 0 {
+            self.message = Some("Select one instance to delete its last prompt".to_string());
+            return;
+        }
+
+        let idx = selected as usize;
+        let ins = &self.instances[idx];
+        match ins.prompts.last() {
+            Some(_) if ins.prompts.len() == 1 => self.remove_instance(idx),
+            Some(&prompt) => {
+                let is_manual =
+                    matches!(prompt, Prompt::Box(_)) && ins.box_manual.last() == Some(&true);
+                self.perform(Edit::RemovePrompt(idx, prompt, is_manual));
+            }
+            None => (),
+        }
     }
 
     // self.reset_instance();
@@ -155,14 +336,60 @@ impl UiState {
         self.instances = Vec::new();
         self.selection = Vec::new();
         self.select_all = true;
+        self.history.clear();
+    }
+
+    fn new_id(&mut self) -> InstanceId {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn remove_edit(&self, idx: usize) -> Edit {
+        Edit::RemoveInstance(idx, self.instances[idx].clone(), self.selection[idx])
+    }
+}
+
+// History related
+impl UiState {
+    // apply the edit and record it for undo
+    pub fn perform(&mut self, edit: Edit) {
+        if let Edit::Batch(edits) = &edit {
+            if edits.is_empty() {
+                return;
+            }
+        }
+
+        edit.apply(self);
+        self.history.push(edit);
+    }
+
+    pub fn undo(&mut self) {
+        if let Some(edit) = self.history.pop_undo() {
+            edit.revert(self);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(edit) = self.history.pop_redo() {
+            edit.apply(self);
+        }
     }
 }
 
 // Ui related
 impl UiState {
-    fn add_instance(&mut self, instance: Instance) {
-        self.instances.push(instance);
-        self.selection.push(self.select_all);
+    fn add_manual_instance(&mut self, mut instance: Instance) {
+        instance.class_id = self.current_class;
+        self.add_instance(instance);
+    }
+
+    fn add_instance(&mut self, mut instance: Instance) {
+        instance.id = self.new_id();
+        self.perform(Edit::AddInstance(
+            self.instances.len(),
+            instance,
+            self.select_all,
+        ));
     }
 
     pub fn draw_prompts(&self, painter: &egui::Painter) {
@@ -187,16 +414,26 @@ impl UiState {
                     painter,
                     self.img_ori_size.as_ref().unwrap(),
                     self.img_pos.as_ref().unwrap(),
+                    self.classes.color(ins.class_id),
                 );
             }
         }
     }
 
-    pub fn format_prompts(&self) -> Vec<Vec<Prompt>> {
+    // only the instances whose prompts have changed since the last segmentation,
+    // with the candidate the user has selected so that it is the one refined
+    pub fn format_prompts(&self) -> Vec<(InstanceId, Vec<Prompt>, usize)> {
         let prompts = self
             .instances
             .iter()
-            .map(|ins| ins.prompts.clone())
+            .filter(|ins| ins.is_dirty())
+            .map(|ins| {
+                (
+                    ins.id,
+                    ins.prompts.clone(),
+                    ins.masks.get().map_or(0, |c| c.rank),
+                )
+            })
             .collect();
 
         prompts
@@ -204,12 +441,13 @@ impl UiState {
 
     // if selecton < 0, add a new instance
     // else update the selected one
-    pub fn pointed(&mut self, point: [f32; 2]) {
-        let label = 1.0f32; // TODO: 0.0 for background
+    // a negative point alone does not make an instance, it always goes to the selected one
+    pub fn pointed(&mut self, point: [f32; 2], label: PointLabel) {
+        let label = label.value();
 
-        if self.operation_mode == OptMode::NewInstance {
+        if self.operation_mode == OptMode::NewInstance && label > 0.0 {
             // add a new instance
-            self.add_instance(Instance::new_point(point[0], point[1], label));
+            self.add_manual_instance(Instance::new_point(point[0], point[1], label));
         } else {
             // do not add new instance
             if self.selection_mode {
@@ -217,11 +455,14 @@ impl UiState {
                 self.find_instance(point);
             } else {
                 // add prompt to selected isntance
-                if self.operation_mode == OptMode::AddOn {
+                if self.operation_mode == OptMode::AddOn || label == 0.0 {
                     let selected = self.check_selection();
                     if selected >= 0 {
-                        self.instances[selected as usize]
-                            .add_point_label(point[0], point[1], label);
+                        let prompt = Prompt::new_point(point[0], point[1], label);
+                        self.perform(Edit::AddPrompt(selected as usize, prompt, false));
+                    } else if label == 0.0 {
+                        self.message =
+                            Some("Select one instance to add a negative point".to_string());
                     }
                 }
             }
@@ -238,9 +479,10 @@ impl UiState {
         };
 
         if selection < 0 {
-            self.add_instance(Instance::new_box(bbox, is_manual));
+            self.add_manual_instance(Instance::new_box(bbox, is_manual));
         } else {
-            self.instances[selection as usize].add_box(bbox, is_manual);
+            let prompt = Prompt::new_box(bbox[0], bbox[1], bbox[2], bbox[3]);
+            self.perform(Edit::AddPrompt(selection as usize, prompt, is_manual));
         }
     }
 }
@@ -267,50 +509,52 @@ impl UiState {
         }
     }
 
+    // the next images in the folder, empty if there is no folder
+    pub fn prefetch_paths(&self) -> Vec<PathBuf> {
+        match self.file_index {
+            Some(idx) if idx < self.file_paths.len() => {
+                let end = (idx + self.prefetch).min(self.file_paths.len());
+                self.file_paths[idx..end].to_vec()
+            }
+            _ => Vec::new(),
+        }
+    }
+
     pub fn open_folder(&mut self) {
         let folder = rfd::FileDialog::new()
             .set_title("Select a folder of images")
             .pick_folder();
 
-        // store file paths and reset the index
-        self.file_paths = match folder {
-            Some(path) => {
-                self.file_index = Some(0);
-                let mut paths = vec![];
-                for entry in walkdir::WalkDir::new(path) {
-                    // peresrve only jpg jpeg png files
-                    let entry = entry.unwrap();
-                    if let Some(ext) = entry.path().extension() {
-                        if ext == "jpg" || ext == "jpeg" || ext == "png" {
-                            paths.push(entry.path().to_path_buf());
-                        }
-                    }
-                }
-                self.file_index = Some(0);
-                paths
-            }
+        match folder {
+            Some(path) => self.set_folder(&path),
             None => {
                 self.file_index = None;
-                vec![]
+                self.file_paths = vec![];
             }
-        };
+        }
+    }
+
+    // store file paths and reset the index
+    pub fn set_folder(&mut self, path: &std::path::Path) {
+        self.file_paths = crate::utils::image_paths(path);
+        self.file_index = Some(0);
     }
 
-    pub fn save_mask(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn save_mask(&self) -> Result<()> {
         match &self.img_path {
             None => (),
             Some(path) => {
                 let save_name = path.file_stem().unwrap();
-                let file = rfd::FileDialog::new()
+                let mut dialog = rfd::FileDialog::new()
                     .set_title("Save As")
-                    .set_file_name(format!("{}.txt", save_name.to_string_lossy()))
-                    .save_file();
+                    .set_file_name(format!("{}.txt", save_name.to_string_lossy()));
+                if let Some(dir) = &self.output_dir {
+                    dialog = dialog.set_directory(dir);
+                }
+                let file = dialog.save_file();
 
                 if let Some(path) = file {
-                    let mut f = File::create(path)?;
-                    for line in self.format_txt() {
-                        writeln!(f, "{}", line)?;
-                    }
+                    annotation::save_txt(&path, &self.format_txt())?;
                 }
             }
         }
@@ -338,6 +582,25 @@ impl fmt::Display for PromptType {
     }
 }
 
+impl PointLabel {
+    // the label of the point prompt for SAM
+    pub fn value(&self) -> f32 {
+        match self {
+            PointLabel::Positive => 1.0,
+            PointLabel::Negative => 0.0,
+        }
+    }
+}
+
+impl fmt::Display for PointLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PointLabel::Positive => write!(f, "Positive"),
+            PointLabel::Negative => write!(f, "Negative"),
+        }
+    }
+}
+
 impl fmt::Display for PromptHover {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
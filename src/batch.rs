@@ -0,0 +1,332 @@
+//! Labeling images without the ui: detection, then SAM with the boxes as prompts.
+
+use crate::annotation::{self, Outline};
+use crate::config::{Config, DetectionConfig};
+use crate::error::{Error, Result};
+use crate::image_loader::Image;
+use crate::model::{
+    sam::{prompt::Prompt, MaskCandidate},
+    yolo::Detection,
+    Models,
+};
+
+use serde::Serialize;
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// What to label and where the labels go.
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// A folder, searched recursively, or one image.
+    pub input: PathBuf,
+    /// The txt files are next to the images if None.
+    pub output: Option<PathBuf>,
+    /// Images with a txt file already are not labeled again.
+    pub skip_existing: bool,
+    /// The summary as json.
+    pub report: Option<PathBuf>,
+}
+
+/// The outcome of a run, printed at the end and saved as the report.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Images found in the input.
+    pub images: usize,
+    /// Images whose txt file was written.
+    pub labeled: usize,
+    /// Labeled without any instance, the txt file is empty.
+    pub empty: usize,
+    /// Images left out because they already had a txt file.
+    pub skipped: usize,
+    /// The lines written, for all images.
+    pub instances: usize,
+    /// The images that failed, the others went on.
+    pub failed: Vec<Failure>,
+    /// The time of the whole run.
+    pub seconds: f64,
+}
+
+/// An image that could not be labeled.
+#[derive(Debug, Serialize)]
+pub struct Failure {
+    /// The image.
+    pub path: PathBuf,
+    /// Why it failed.
+    pub error: String,
+}
+
+/// Labels the images of a folder as YOLO segmentation txt files.
+///
+/// The masks of segmentation detectors are kept unless `sam_refine` is set,
+/// the other boxes are segmented by SAM and the best candidate is used.
+pub struct Batch {
+    models: Models,
+    params: DetectionConfig,
+    options: BatchOptions,
+}
+
+impl Batch {
+    /// Load the models of the config, the input must exist.
+    pub fn new(config: &Config, options: BatchOptions) -> Result<Self> {
+        if !options.input.exists() {
+            return Err(Error::Config(format!(
+                "the batch input {} does not exist",
+                options.input.display()
+            )));
+        }
+
+        // every image is embedded once, caching the embeddings would only fill the disk
+        let mut config = config.clone();
+        config.cache.enabled = false;
+
+        Ok(Self {
+            models: Models::new(&config)?,
+            params: config.detection,
+            options,
+        })
+    }
+
+    /// Label every image, an image that fails is reported in the summary and the others go on.
+    pub fn run(&mut self) -> Result<Summary> {
+        let timer = Instant::now();
+        let paths = crate::utils::image_paths(&self.options.input);
+
+        let mut summary = Summary {
+            images: paths.len(),
+            ..Default::default()
+        };
+        for (i, path) in paths.iter().enumerate() {
+            let txt = self.label_path(path);
+            let progress = format!("[{}/{}] {}", i + 1, paths.len(), path.display());
+            if self.options.skip_existing && txt.exists() {
+                println!("{progress}: skipped, {} exists", txt.display());
+                summary.skipped += 1;
+                continue;
+            }
+
+            match self
+                .label(path)
+                .and_then(|lines| annotation::save_txt(&txt, &lines).map(|_| lines))
+            {
+                Ok(lines) => {
+                    println!("{progress}: {} instances", lines.len());
+                    summary.labeled += 1;
+                    summary.instances += lines.len();
+                    if lines.is_empty() {
+                        summary.empty += 1;
+                    }
+                }
+                Err(e) => {
+                    println!("{progress}: {e}");
+                    summary.failed.push(Failure {
+                        path: path.clone(),
+                        error: e.to_string(),
+                    });
+                }
+            }
+        }
+        summary.seconds = timer.elapsed().as_secs_f64();
+
+        if let Some(report) = &self.options.report {
+            summary.save(report)?;
+        }
+
+        Ok(summary)
+    }
+
+    /// The lines of the txt file of one image.
+    pub fn label(&mut self, path: &Path) -> Result<Vec<String>> {
+        let img = Image::load(path.to_path_buf())?;
+        let mut detections = self.models.detect(&img.data, &self.params)?;
+        detections.retain(Self::has_area);
+
+        let mut outlines = vec![None; detections.len()];
+        let mut unsegmented = Vec::new();
+        for (i, det) in detections.iter_mut().enumerate() {
+            match det.mask.take().and_then(|mask| mask.outline()) {
+                Some(outline) if !self.params.sam_refine => {
+                    outlines[i] = Some(outline.normalize(img.size))
+                }
+                _ => unsegmented.push(i),
+            }
+        }
+
+        // SAM only runs for the boxes without a mask
+        if !unsegmented.is_empty() {
+            self.models.embed(&img.data)?;
+        }
+        for chunk in unsegmented.chunks(self.models.batch_size()) {
+            let batch = chunk
+                .iter()
+                .map(|&i| vec![Prompt::from(detections[i].bbox)])
+                .collect();
+            let results = self.models.generate_masks(batch)?;
+            for (&i, candidates) in chunk.iter().zip(results) {
+                outlines[i] = Self::best_outline(candidates, img.size);
+            }
+        }
+
+        // instances whose masks are empty are left out
+        Ok(detections
+            .iter()
+            .zip(outlines)
+            .filter_map(|(det, outline)| Some(annotation::yolo_line(det.class_id, &outline?)))
+            .collect())
+    }
+}
+
+// private
+impl Batch {
+    // the detectors drop them, the mock and other detectors may not
+    fn has_area(det: &Detection) -> bool {
+        det.bbox.has_area()
+    }
+
+    // the candidates are sorted, the best one whose mask is not empty
+    fn best_outline(candidates: Vec<MaskCandidate>, img_size: [f32; 2]) -> Option<Outline> {
+        candidates
+            .into_iter()
+            .find_map(|c| Outline::from(&c.mask.to_luma8()))
+            .map(|outline| outline.normalize(img_size))
+    }
+
+    // the same relative path as the image in the output folder
+    fn label_path(&self, img: &Path) -> PathBuf {
+        let txt = img.with_extension("txt");
+        match &self.options.output {
+            None => txt,
+            Some(output) => {
+                let relative = txt
+                    .strip_prefix(&self.options.input)
+                    .ok()
+                    .filter(|r| !r.as_os_str().is_empty());
+                match relative {
+                    Some(relative) => output.join(relative),
+                    None => output.join(txt.file_name().unwrap_or_default()),
+                }
+            }
+        }
+    }
+}
+
+impl Summary {
+    fn save(&self, path: &Path) -> Result<()> {
+        let file = fs::File::create(path).map_err(|e| Error::Export(path.to_path_buf(), e))?;
+        serde_json::to_writer_pretty(file, self)
+            .map_err(|e| Error::Export(path.to_path_buf(), e.into()))
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Labeled {} of {} images with {} instances in {:.1}s ({} without instances, {} skipped, {} failed)",
+            self.labeled,
+            self.images,
+            self.instances,
+            self.seconds,
+            self.empty,
+            self.skipped,
+            self.failed.len()
+        )?;
+        for failure in &self.failed {
+            write!(f, "\n  {}: {}", failure.path.display(), failure.error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_with_mock_models() {
+        let dir = std::env::temp_dir().join(format!("label-sam-batch-{}", std::process::id()));
+        let (input, output) = (dir.join("images"), dir.join("labels"));
+        fs::create_dir_all(input.join("sub")).unwrap();
+        image::RgbImage::new(64, 48)
+            .save(input.join("a.png"))
+            .unwrap();
+        image::RgbImage::new(32, 32)
+            .save(input.join("sub").join("b.jpg"))
+            .unwrap();
+        fs::write(input.join("notes.txt"), "not an image").unwrap();
+
+        let config: Config = serde_json::from_str(
+            r#"{
+                "yolo_path": "", "sam_e_path": "", "sam_d_path": "",
+                "detector": "mock", "segmenter": "mock"
+            }"#,
+        )
+        .unwrap();
+        let options = BatchOptions {
+            input: input.clone(),
+            output: Some(output.clone()),
+            skip_existing: true,
+            report: Some(dir.join("report.json")),
+        };
+
+        let summary = Batch::new(&config, options.clone()).unwrap().run().unwrap();
+        assert_eq!(summary.images, 2);
+        assert_eq!(summary.labeled, 2);
+        assert_eq!(summary.instances, 4);
+        assert!(summary.failed.is_empty());
+
+        // one line for each mock box, the class first
+        let txt = fs::read_to_string(output.join("sub").join("b.txt")).unwrap();
+        let classes: Vec<_> = txt.lines().map(|l| l.split(' ').next().unwrap()).collect();
+        assert_eq!(classes, vec!["0", "1"]);
+        assert!(dir.join("report.json").exists());
+
+        let summary = Batch::new(&config, options).unwrap().run().unwrap();
+        assert_eq!(summary.skipped, 2);
+        assert_eq!(summary.labeled, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flat_boxes_and_empty_masks_are_left_out() {
+        let detection = |x1, y1, x2, y2| Detection {
+            bbox: crate::model::yolo::BoundingBox::new(x1, y1, x2, y2),
+            conf: 0.9,
+            class_id: 0,
+            mask: None,
+        };
+        assert!(Batch::has_area(&detection(0.1, 0.1, 0.2, 0.3)));
+        assert!(!Batch::has_area(&detection(0.1, 0.1, 0.1, 0.3)));
+        assert!(!Batch::has_area(&detection(0.1, 0.3, 0.2, 0.3)));
+
+        let candidate = |filled: bool, iou| {
+            let mut mask = image::GrayImage::new(80, 40);
+            if filled {
+                for (x, y) in (20..60).flat_map(|x| (8..32).map(move |y| (x, y))) {
+                    mask.put_pixel(x, y, image::Luma([255]));
+                }
+            }
+            MaskCandidate {
+                mask: image::DynamicImage::ImageLuma8(mask),
+                iou,
+                stability: 1.0,
+            }
+        };
+        // the best candidate is empty, the next one is used
+        let outline = Batch::best_outline(
+            vec![candidate(false, 0.9), candidate(true, 0.5)],
+            [80.0, 40.0],
+        )
+        .unwrap();
+        assert!(outline
+            .points()
+            .iter()
+            .all(|p| (0.2..=0.8).contains(&p[0]) && (0.1..=0.9).contains(&p[1])));
+        assert!(Batch::best_outline(vec![candidate(false, 0.9)], [80.0, 40.0]).is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use label_sam::batch::BatchOptions;
+
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Map, Value};
+
+use std::path::PathBuf;
+
+// without a subcommand the ui is started,
+// the flags override config.json, the user config and the environment
+#[derive(Debug, Parser)]
+#[command(name = "label-sam", version, about = "Label images with SAM and YOLO")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Project config file [default: config.json in the current folder]
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Detection model
+    #[arg(long, global = true)]
+    pub yolo: Option<PathBuf>,
+
+    /// SAM encoder model
+    #[arg(long, global = true)]
+    pub sam_encoder: Option<PathBuf>,
+
+    /// SAM decoder model
+    #[arg(long, global = true)]
+    pub sam_decoder: Option<PathBuf>,
+
+    /// Execution providers to try in order, e.g. cuda,cpu
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub provider: Vec<String>,
+
+    /// Folder for the txt files [default: next to the images]
+    #[arg(short, long, global = true)]
+    pub output: Option<PathBuf>,
+
+    /// Folder of images to open when the ui starts
+    #[arg(long)]
+    pub open: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Label every image of a folder without the ui, as YOLO segmentation txt files
+    Batch(BatchArgs),
+}
+
+// the thresholds left out come from the config
+#[derive(Debug, Args)]
+pub struct BatchArgs {
+    /// Folder of jpg, jpeg and png images, searched recursively, or a single image
+    pub input: PathBuf,
+
+    /// Minimum confidence of a detection
+    #[arg(long)]
+    pub conf: Option<f32>,
+
+    /// IoU threshold of the non-maximum suppression
+    #[arg(long)]
+    pub iou: Option<f32>,
+
+    /// Maximum number of detections per image, 0 for no limit
+    #[arg(long)]
+    pub max_detections: Option<usize>,
+
+    /// Segment the boxes with SAM even if the detector gives masks
+    #[arg(long, overrides_with = "no_sam_refine")]
+    pub sam_refine: bool,
+
+    /// Keep the masks of the detector, when sam_refine is set in the config
+    #[arg(long, overrides_with = "sam_refine")]
+    pub no_sam_refine: bool,
+
+    /// Do not label images that already have a txt file
+    #[arg(long)]
+    pub skip_existing: bool,
+
+    /// Write the summary to a json file
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+}
+
+impl Cli {
+    // the config layer of the flags, with only the keys that are given
+    pub fn overrides(&self) -> Value {
+        let mut layer = Map::new();
+        for (key, path) in [
+            ("yolo_path", &self.yolo),
+            ("sam_e_path", &self.sam_encoder),
+            ("sam_d_path", &self.sam_decoder),
+            ("output_dir", &self.output),
+        ] {
+            if let Some(path) = path {
+                layer.insert(key.to_string(), json!(path.display().to_string()));
+            }
+        }
+        if !self.provider.is_empty() {
+            layer.insert("session".to_string(), json!({ "providers": self.provider }));
+        }
+
+        if let Some(Command::Batch(args)) = &self.command {
+            let mut detection = Map::new();
+            if let Some(conf) = args.conf {
+                detection.insert("conf_threshold".to_string(), json!(conf));
+            }
+            if let Some(iou) = args.iou {
+                detection.insert("iou_threshold".to_string(), json!(iou));
+            }
+            if let Some(max) = args.max_detections {
+                detection.insert("max_detections".to_string(), json!(max));
+            }
+            // the last of the two flags wins
+            if args.sam_refine || args.no_sam_refine {
+                detection.insert("sam_refine".to_string(), json!(args.sam_refine));
+            }
+            if !detection.is_empty() {
+                layer.insert("detection".to_string(), Value::Object(detection));
+            }
+        }
+
+        Value::Object(layer)
+    }
+}
+
+impl BatchArgs {
+    // the output folder is the one of the config
+    pub fn options(self, output: Option<PathBuf>) -> BatchOptions {
+        BatchOptions {
+            input: self.input,
+            output,
+            skip_existing: self.skip_existing,
+            report: self.report,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sam_refine(args: &[&str]) -> Value {
+        let cli = Cli::try_parse_from([&["label-sam", "batch", "images"], args].concat()).unwrap();
+        cli.overrides()["detection"]["sam_refine"].clone()
+    }
+
+    #[test]
+    fn sam_refine_can_be_turned_off() {
+        assert_eq!(sam_refine(&[]), Value::Null);
+        assert_eq!(sam_refine(&["--sam-refine"]), json!(true));
+        assert_eq!(sam_refine(&["--no-sam-refine"]), json!(false));
+        assert_eq!(
+            sam_refine(&["--sam-refine", "--no-sam-refine"]),
+            json!(false)
+        );
+        assert_eq!(
+            sam_refine(&["--no-sam-refine", "--sam-refine"]),
+            json!(true)
+        );
+    }
+}
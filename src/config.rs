@@ -1,16 +1,533 @@
+//! The settings of the app and the batch mode, layered from the user config, config.json,
+//! the environment and the command line.
+
+use core::fmt;
+
+use crate::error::{Error, Result};
+
 use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use std::path::{Path, PathBuf};
+
+// the prefix of the environment variables that override the config,
+// nested keys are separated by a double underscore, e.g. LABEL_SAM_DETECTION__CONF_THRESHOLD
+const ENV_PREFIX: &str = "LABEL_SAM_";
+
+// the keys holding paths, relative ones are resolved from the folder of the config file
+const PATH_KEYS: [&[&str]; 6] = [
+    &["yolo_path"],
+    &["sam_e_path"],
+    &["sam_d_path"],
+    &["classes_path"],
+    &["output_dir"],
+    &["cache", "dir"],
+];
 
-#[derive(Debug, Deserialize)]
+/// The settings of the app and the batch mode, see [`Config::load`] for where they come from.
+#[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// The detection model, not needed by the mock detector.
+    #[serde(default)]
     pub yolo_path: String,
+    /// The SAM encoder, not needed by the mock segmenter.
+    #[serde(default)]
     pub sam_e_path: String,
+    /// The SAM decoder, not needed by the mock segmenter.
+    #[serde(default)]
     pub sam_d_path: String,
+
+    /// Which implementation loads `yolo_path`.
+    #[serde(default)]
+    pub detector: DetectorKind,
+    /// Which implementation loads the SAM encoder and decoder.
+    #[serde(default)]
+    pub segmenter: SegmenterKind,
+
+    /// Options of every onnx session.
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// The default thresholds of the detector.
+    #[serde(default)]
+    pub detection: DetectionConfig,
+    /// The on-disk cache of image embeddings.
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Segmenting everything in an image.
+    #[serde(default)]
+    pub auto: AutoMaskConfig,
+
+    /// Overrides the class names stored in the detection model.
+    #[serde(default)]
+    pub class_names: Vec<String>,
+    /// A txt file with one class per line, overrides `class_names`.
+    #[serde(default)]
+    pub classes_path: Option<String>,
+    /// Where the txt files are saved, next to the images if None.
+    #[serde(default)]
+    pub output_dir: Option<String>,
+}
+
+/// Options shared by every onnx session the models create.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Tried in order, the first one that registers is used,
+    /// cpu is always tried last even if it is not listed.
+    pub providers: Vec<Provider>,
+    /// Threads used within an operator, 0 lets onnxruntime choose.
+    pub intra_threads: usize,
+    /// Threads used between operators, 0 lets onnxruntime choose.
+    pub inter_threads: usize,
+}
+
+/// Thresholds for yolo postprocess, can be changed from the ui.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DetectionConfig {
+    /// Detections below it are dropped.
+    pub conf_threshold: f32,
+    /// Boxes of the same class overlapping more than it are suppressed.
+    pub iou_threshold: f32,
+    /// 0 for no limit.
+    pub max_detections: usize,
+    /// Segment the detections with SAM, even if the detector gives masks.
+    pub sam_refine: bool,
+}
+
+/// Segment everything with a point grid, can be changed from the ui.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AutoMaskConfig {
+    /// The grid has points_per_side x points_per_side points, halved for each crop layer.
+    pub points_per_side: usize,
+    /// Masks whose IoU predicted by SAM is below it are dropped.
+    pub iou_threshold: f32,
+    /// Masks whose stability score is below it are dropped.
+    pub stability_threshold: f32,
+    /// Masks overlapping more than it are duplicates.
+    pub nms_threshold: f32,
+    /// 0 for the whole image only, layer n adds 2^n x 2^n crops.
+    ///
+    /// Masks cut by the inner edge of a crop are dropped, the larger crops find them whole.
+    pub crop_layers: usize,
+    /// The overlap of crops, relative to the short side of the image.
+    pub crop_overlap: f32,
+}
+
+/// The on-disk cache of SAM image embeddings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Whether embeddings are read from and written to the cache.
+    pub enabled: bool,
+    /// The folder of the cache.
+    pub dir: String,
+    /// The least recently used embeddings are removed above it.
+    pub max_size_mb: u64,
+    /// The number of next images in the folder to load and embed ahead.
+    pub prefetch: usize,
+}
+
+/// The format of the detection model.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectorKind {
+    /// Boxes with an objectness score.
+    Yolov5,
+    /// YOLOv8 and YOLOv11 exports share the output layout.
+    #[default]
+    #[serde(alias = "yolov11")]
+    Yolov8,
+    /// End-to-end, no nms.
+    RtDetr,
+    /// Fixed boxes without a model file, for testing.
+    Mock,
+}
+
+/// The format of the segmentation models.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmenterKind {
+    /// MobileSAM exports have the same inputs and outputs, the other families are found
+    /// from the encoder.
+    #[default]
+    #[serde(alias = "mobile_sam")]
+    Sam,
+    /// SAM2 and SAM2.1 exports with the high-res features.
+    #[serde(alias = "sam2.1")]
+    Sam2,
+    /// SAM-HQ exports with the intermediate embeddings.
+    SamHq,
+    /// EfficientSAM exports split into an encoder and a decoder.
+    EfficientSam,
+    /// Box shaped masks without a model file, for testing.
+    Mock,
 }
 
+/// An onnxruntime execution provider, only those compiled in can register.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// Always available.
+    Cpu,
+    /// NVIDIA GPUs.
+    Cuda,
+    /// NVIDIA GPUs with TensorRT.
+    TensorRT,
+    /// Intel CPUs and GPUs.
+    OpenVINO,
+    /// GPUs on Windows.
+    DirectML,
+    /// Apple devices.
+    CoreML,
+}
+
+impl Config {
+    /// The defaults, then the user config, the project file, the environment and the command line,
+    /// each one overrides the keys it sets. The project file is config.json in the current folder
+    /// if no path is given.
+    pub fn load(path: Option<&Path>, overrides: Value) -> Result<Config> {
+        let mut layers = Vec::new();
+        if let Some(user) = Self::user_path().filter(|p| p.exists()) {
+            layers.push((user.display().to_string(), Self::read_layer(&user)?));
+        }
+        match path {
+            Some(path) if !path.exists() => {
+                return Err(Error::Config(format!(
+                    "the config file {} does not exist",
+                    path.display()
+                )))
+            }
+            Some(path) => layers.push((path.display().to_string(), Self::read_layer(path)?)),
+            None => {
+                let project = Path::new("config.json");
+                if project.exists() {
+                    layers.push(("config.json".to_string(), Self::read_layer(project)?));
+                }
+            }
+        }
+        layers.push(("the environment".to_string(), env_layer(std::env::vars())));
+        layers.push(("the command line".to_string(), overrides));
+
+        Self::from_layers(layers)
+    }
+
+    /// `label-sam/config.json` in the config folder of the user, e.g. ~/.config on linux.
+    pub fn user_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("label-sam").join("config.json"))
+    }
+
+    /// The problems that would only show up later, all at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        let mut models = Vec::new();
+        if self.detector != DetectorKind::Mock {
+            models.push(("yolo_path", &self.yolo_path, "--yolo"));
+        }
+        if self.segmenter != SegmenterKind::Mock {
+            models.push(("sam_e_path", &self.sam_e_path, "--sam-encoder"));
+            models.push(("sam_d_path", &self.sam_d_path, "--sam-decoder"));
+        }
+        for (key, path, flag) in models {
+            let env = format!("{ENV_PREFIX}{}", key.to_uppercase());
+            if path.is_empty() {
+                problems.push(format!(
+                    "{key} is not set, set it in config.json, {env} or with {flag}"
+                ));
+            } else if !Path::new(path).exists() {
+                problems.push(format!(
+                    "{key} does not exist: {path}, fix it in config.json, {env} or with {flag}"
+                ));
+            }
+        }
+        // a missing classes_path is not a problem, the ui says so and uses the model names
+
+        for (key, value) in [
+            ("detection.conf_threshold", self.detection.conf_threshold),
+            ("detection.iou_threshold", self.detection.iou_threshold),
+            ("auto.iou_threshold", self.auto.iou_threshold),
+            ("auto.stability_threshold", self.auto.stability_threshold),
+            ("auto.nms_threshold", self.auto.nms_threshold),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                problems.push(format!("{key} is {value}, it must be between 0 and 1"));
+            }
+        }
+        if self.auto.points_per_side == 0 {
+            problems.push("auto.points_per_side must be at least 1".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(problems.join("\n  ")))
+        }
+    }
+}
+
+// private
 impl Config {
-    pub fn new() -> Config {
-        let json_content = std::fs::read_to_string("config.json").unwrap();
+    // merge the layers in order, the name of each one is used in messages
+    fn from_layers(layers: Vec<(String, Value)>) -> Result<Config> {
+        let mut merged = Value::Object(Map::new());
+        let mut sources = vec!["the defaults".to_string()];
+        for (name, layer) in layers {
+            if layer.as_object().is_some_and(|o| !o.is_empty()) {
+                sources.push(name);
+            }
+            merge(&mut merged, layer);
+        }
+
+        let config: Config = serde_json::from_value(merged).map_err(|e| {
+            Error::Config(format!("{e}, the config is from {}", sources.join(", ")))
+        })?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn read_layer(path: &Path) -> Result<Value> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("failed to read {}: {e}", path.display())))?;
+        let mut layer: Value = serde_json::from_str(&content)
+            .map_err(|e| Error::Config(format!("failed to parse {}: {e}", path.display())))?;
+        if !layer.is_object() {
+            return Err(Error::Config(format!(
+                "{} should contain a json object",
+                path.display()
+            )));
+        }
+
+        if let Some(base) = path.parent() {
+            resolve_paths(&mut layer, base);
+        }
+
+        Ok(layer)
+    }
+}
+
+// the keys of the layer override the ones of base, objects are merged key by key
+fn merge(base: &mut Value, layer: Value) {
+    match (base, layer) {
+        (Value::Object(base), Value::Object(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    Some(old) => merge(old, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, layer) => *base = layer,
+    }
+}
+
+// the variables with ENV_PREFIX as a layer, a value is parsed as json if it can be, as a string otherwise
+fn env_layer(vars: impl Iterator<Item = (String, String)>) -> Value {
+    let mut layer = Value::Object(Map::new());
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+        let nested = key.to_lowercase().rsplit("__").fold(value, |value, key| {
+            let mut object = Map::new();
+            object.insert(key.to_string(), value);
+            Value::Object(object)
+        });
+        merge(&mut layer, nested);
+    }
+
+    layer
+}
+
+// relative paths in a config file are relative to the file, not to the current folder
+fn resolve_paths(layer: &mut Value, base: &Path) {
+    for keys in PATH_KEYS {
+        let mut value = Some(&mut *layer);
+        for key in keys {
+            value = value.and_then(|v| v.get_mut(*key));
+        }
+        if let Some(Value::String(path)) = value {
+            if Path::new(path.as_str()).is_relative() {
+                *path = base.join(path.as_str()).display().to_string();
+            }
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            providers: vec![Provider::Cuda, Provider::Cpu],
+            intra_threads: 4,
+            inter_threads: 1,
+        }
+    }
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        DetectionConfig {
+            conf_threshold: 0.5,
+            iou_threshold: 0.7,
+            max_detections: 300,
+            sam_refine: false,
+        }
+    }
+}
+
+impl Default for AutoMaskConfig {
+    fn default() -> Self {
+        AutoMaskConfig {
+            points_per_side: 16,
+            iou_threshold: 0.88,
+            stability_threshold: 0.95,
+            nms_threshold: 0.7,
+            crop_layers: 0,
+            crop_overlap: 512.0 / 1500.0,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            dir: "cache".to_string(),
+            max_size_mb: 1024,
+            prefetch: 2,
+        }
+    }
+}
+
+impl fmt::Display for Provider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Provider::Cpu => write!(f, "CPU"),
+            Provider::Cuda => write!(f, "CUDA"),
+            Provider::TensorRT => write!(f, "TensorRT"),
+            Provider::OpenVINO => write!(f, "OpenVINO"),
+            Provider::DirectML => write!(f, "DirectML"),
+            Provider::CoreML => write!(f, "CoreML"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let layers = vec![
+            (
+                "user".to_string(),
+                json!({"detector": "mock", "segmenter": "mock", "detection": {"conf_threshold": 0.3, "max_detections": 10}}),
+            ),
+            (
+                "project".to_string(),
+                json!({"detection": {"conf_threshold": 0.4}}),
+            ),
+            (
+                "env".to_string(),
+                env_layer(
+                    [
+                        ("LABEL_SAM_DETECTION__IOU_THRESHOLD", "0.6"),
+                        ("LABEL_SAM_SESSION__PROVIDERS", r#"["cpu"]"#),
+                        ("OTHER", "1"),
+                    ]
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string())),
+                ),
+            ),
+            ("cli".to_string(), json!({"output_dir": "labels"})),
+        ];
+
+        let config = Config::from_layers(layers).unwrap();
+        assert_eq!(config.detection.conf_threshold, 0.4);
+        assert_eq!(config.detection.iou_threshold, 0.6);
+        assert_eq!(config.detection.max_detections, 10);
+        assert_eq!(config.session.providers, vec![Provider::Cpu]);
+        assert_eq!(config.output_dir.as_deref(), Some("labels"));
+    }
+
+    #[test]
+    fn env_values_are_json_or_strings() {
+        let layer = env_layer(
+            [
+                ("LABEL_SAM_YOLO_PATH", "weights/yolo.onnx"),
+                ("LABEL_SAM_CACHE__ENABLED", "false"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+        assert_eq!(
+            layer,
+            json!({"yolo_path": "weights/yolo.onnx", "cache": {"enabled": false}})
+        );
+    }
+
+    #[test]
+    fn relative_paths_follow_the_config_file() {
+        let mut layer = json!({"yolo_path": "weights/yolo.onnx", "sam_e_path": "/abs/e.onnx", "cache": {"dir": "cache"}});
+        resolve_paths(&mut layer, Path::new("project"));
+        assert_eq!(
+            layer["yolo_path"],
+            json!(Path::new("project")
+                .join("weights/yolo.onnx")
+                .display()
+                .to_string())
+        );
+        assert_eq!(layer["sam_e_path"], json!("/abs/e.onnx"));
+        assert_eq!(
+            layer["cache"]["dir"],
+            json!(Path::new("project").join("cache").display().to_string())
+        );
+    }
+
+    #[test]
+    fn validation_names_the_problem() {
+        let layers = vec![(
+            "project".to_string(),
+            json!({"yolo_path": "missing.onnx", "segmenter": "mock", "detection": {"conf_threshold": 2.0}}),
+        )];
+
+        let message = Config::from_layers(layers).unwrap_err().to_string();
+        assert!(
+            message.contains("yolo_path does not exist: missing.onnx"),
+            "{message}"
+        );
+        assert!(
+            message.contains("detection.conf_threshold is 2"),
+            "{message}"
+        );
+        assert!(!message.contains("sam_e_path"), "{message}");
+
+        // the ui falls back to the model names without the class file
+        let layers = vec![(
+            "project".to_string(),
+            json!({"detector": "mock", "segmenter": "mock", "classes_path": "missing.txt"}),
+        )];
+        assert!(Config::from_layers(layers).is_ok());
+    }
 
-        serde_json::from_str(&json_content).unwrap()
+    #[test]
+    fn segmenters_are_parsed_by_name() {
+        let kinds = [
+            ("mobile_sam", SegmenterKind::Sam),
+            ("sam2.1", SegmenterKind::Sam2),
+            ("sam_hq", SegmenterKind::SamHq),
+            ("efficient_sam", SegmenterKind::EfficientSam),
+        ];
+        for (name, kind) in kinds {
+            let parsed: SegmenterKind = serde_json::from_value(json!(name)).unwrap();
+            assert_eq!(parsed, kind, "{name}");
+        }
     }
 }
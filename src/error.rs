@@ -0,0 +1,66 @@
+//! The error type of the crate.
+
+use core::fmt;
+use std::path::PathBuf;
+
+/// The result of everything that can fail in the crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// What went wrong, with the file it happened to when there is one.
+#[derive(Debug)]
+pub enum Error {
+    /// An invalid or missing setting.
+    Config(String),
+    /// A model file that onnxruntime can not load, with its path.
+    ModelLoad(String, ort::Error),
+    /// A model whose inputs and outputs are not the expected ones, with its path and the mismatch.
+    Signature(String, String),
+    /// A model that failed to run or gave an unexpected output.
+    Inference(String),
+    /// An image that can not be read or decoded.
+    ImageIo(PathBuf, image::ImageError),
+    /// A file that can not be written.
+    Export(PathBuf, std::io::Error),
+    /// The window or the model thread of the ui that could not start.
+    Ui(String),
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ModelLoad(_, e) => Some(e),
+            Error::ImageIo(_, e) => Some(e),
+            Error::Export(_, e) => Some(e),
+            Error::Config(_) | Error::Signature(..) | Error::Inference(_) | Error::Ui(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(msg) => write!(f, "Config error: {msg}"),
+            Error::ModelLoad(path, e) => write!(f, "Failed to load model {path}: {e}"),
+            Error::Signature(path, msg) => write!(
+                f,
+                "Model {path} does not have the expected inputs and outputs: {msg}"
+            ),
+            Error::Inference(msg) => write!(f, "Inference error: {msg}"),
+            Error::ImageIo(path, e) => write!(f, "Failed to read image {}: {e}", path.display()),
+            Error::Export(path, e) => write!(f, "Failed to export {}: {e}", path.display()),
+            Error::Ui(msg) => write!(f, "Ui error: {msg}"),
+        }
+    }
+}
+
+impl From<ort::Error> for Error {
+    fn from(e: ort::Error) -> Self {
+        Error::Inference(e.to_string())
+    }
+}
+
+impl From<ndarray::ShapeError> for Error {
+    fn from(e: ndarray::ShapeError) -> Self {
+        Error::Inference(e.to_string())
+    }
+}
@@ -0,0 +1,45 @@
+//! Images loaded from disk with the sizes the ui and the models need.
+
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+
+use image::{DynamicImage, ImageError};
+
+/// An image with its path and sizes.
+#[derive(Clone)]
+pub struct Image {
+    /// The decoded pixels.
+    pub data: DynamicImage,
+    /// Where it was loaded from.
+    pub path: PathBuf,
+    /// Width and height in pixels.
+    pub size: [f32; 2],
+    /// In bytes.
+    pub file_size: f32,
+}
+
+impl Image {
+    /// Read and decode an image file.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let data = image::ImageReader::open(&path)
+            .map_err(ImageError::IoError)
+            .and_then(|reader| reader.decode());
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => return Err(Error::ImageIo(path, e)),
+        };
+        let size = [data.width() as f32, data.height() as f32];
+        let file_size = match std::fs::metadata(&path) {
+            Ok(meta) => meta.len() as f32,
+            Err(e) => return Err(Error::ImageIo(path, ImageError::IoError(e))),
+        };
+
+        Ok(Image {
+            data,
+            path,
+            size,
+            file_size,
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! Labeling images for YOLO segmentation with SAM and a detector.
+//!
+//! The crate is used by the `label-sam` binary, whose egui app sits behind the `gui` feature
+//! (on by default), and whose `batch` subcommand only needs the library. Scripts can use the
+//! models and the exporters directly:
+//!
+//! ```no_run
+//! use label_sam::annotation::{self, Outline};
+//! use label_sam::config::{DetectionConfig, SessionConfig};
+//! use label_sam::model::{sam::SAMmodel, yolo::YOLOmodel};
+//! use label_sam::Prompt;
+//!
+//! # fn main() -> label_sam::Result<()> {
+//! let session = SessionConfig::default();
+//! let mut yolo = YOLOmodel::new_path("weights/yolov8s.onnx", &session)?;
+//! let mut sam = SAMmodel::new_path(
+//!     "weights/sam_b-encoder.onnx",
+//!     "weights/sam_b-decoder.onnx",
+//!     &session,
+//!     None,
+//! )?;
+//!
+//! let img = image::open("image.jpg").unwrap();
+//! let size = [img.width() as f32, img.height() as f32];
+//! sam.embed(&img)?;
+//!
+//! let mut lines = Vec::new();
+//! // the boxes of forward have an area, a flat box is not a prompt
+//! for det in yolo.forward(&img, &DetectionConfig::default())? {
+//!     let candidates = sam.generate_mask(vec![Prompt::from(det.bbox)])?;
+//!     if let Some(outline) = candidates
+//!         .first()
+//!         .and_then(|c| Outline::from(&c.mask.to_luma8()))
+//!     {
+//!         lines.push(annotation::yolo_line(det.class_id, &outline.normalize(size)));
+//!     }
+//! }
+//! annotation::save_txt("image.txt".as_ref(), &lines)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`model::Models`] picks the detector and segmenter of a [`config::Config`],
+//! and [`batch::Batch`] labels whole folders with it.
+
+#![warn(missing_docs)]
+
+pub mod annotation;
+#[cfg(feature = "gui")]
+pub mod app;
+pub mod batch;
+pub mod config;
+pub mod error;
+pub mod image_loader;
+pub mod model;
+mod utils;
+
+pub use annotation::{Candidate, Outline};
+pub use config::Config;
+pub use error::{Error, Result};
+pub use model::sam::{prompt::Prompt, SAMmodel};
+pub use model::yolo::{Detection, YOLOmodel};
+pub use model::{Detector, Models, Segmenter};
+pub use utils::image_paths;
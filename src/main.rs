@@ -1,10 +1,51 @@
-use app::App;
+use clap::Parser;
+use label_sam::batch::Batch;
+use label_sam::config::Config;
+use label_sam::error::Result;
+use std::path::PathBuf;
 
-mod app;
-mod config;
-mod utils;
+mod cli;
 
 fn main() {
-    let config = config::Config::new();
-    App::new(config).run();
+    let cli = cli::Cli::parse();
+
+    let config = match Config::load(cli.config.as_deref(), cli.overrides()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let ret = match cli.command {
+        Some(cli::Command::Batch(args)) => {
+            let output = config.output_dir.as_ref().map(PathBuf::from);
+            batch(&config, args.options(output))
+        }
+        None => run_ui(config, cli.open),
+    };
+    if let Err(e) = ret {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+// label the images without the ui and print the summary
+fn batch(config: &Config, options: label_sam::batch::BatchOptions) -> Result<()> {
+    let summary = Batch::new(config, options)?.run()?;
+    println!("{summary}");
+
+    Ok(())
+}
+
+#[cfg(feature = "gui")]
+fn run_ui(config: Config, folder: Option<PathBuf>) -> Result<()> {
+    label_sam::app::App::new(config).with_folder(folder).run()
+}
+
+#[cfg(not(feature = "gui"))]
+fn run_ui(_config: Config, _folder: Option<PathBuf>) -> Result<()> {
+    Err(label_sam::error::Error::Config(
+        "label-sam was built without the gui feature, use `label-sam batch`".to_string(),
+    ))
 }
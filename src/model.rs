@@ -0,0 +1,256 @@
+//! The detection and segmentation models, and [`Models`] that picks them from the config.
+
+use crate::config::{AutoMaskConfig, Config, DetectionConfig, DetectorKind, SegmenterKind};
+use crate::error::{Error, Result};
+use sam::prompt::Prompt;
+
+pub mod mock;
+mod provider;
+pub mod rtdetr;
+pub mod sam;
+pub mod signature;
+pub mod yolo;
+
+pub use provider::SessionProvider;
+
+/// What the ui needs to know about the loaded models.
+pub struct ModelInfo {
+    /// The execution provider each session ended up on.
+    pub providers: Vec<(&'static str, SessionProvider)>,
+    /// Indexed by class id.
+    pub class_names: Vec<String>,
+    /// The inputs, outputs and metadata of every loaded model.
+    pub signatures: Vec<signature::Signature>,
+}
+
+/// Finds boxes of known classes, owns its pre- and post-processing.
+pub trait Detector: Send {
+    /// the sessions of the model and the provider each one runs on
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)>;
+
+    /// the inputs, outputs and metadata of each session
+    fn signatures(&self) -> Vec<signature::Signature>;
+
+    /// indexed by class id, empty if the model does not know them
+    fn class_names(&self) -> &[String];
+
+    /// run the model on an image and keep its output for redetect
+    fn infer(&mut self, img: &image::DynamicImage) -> Result<()>;
+
+    /// the postprocess of the output of the last image, the boxes are normalized to the image
+    fn redetect(&self, params: &DetectionConfig) -> Result<Vec<yolo::Detection>>;
+
+    /// infer and postprocess
+    fn detect(
+        &mut self,
+        img: &image::DynamicImage,
+        params: &DetectionConfig,
+    ) -> Result<Vec<yolo::Detection>> {
+        self.infer(img)?;
+        self.redetect(params)
+    }
+
+    /// the last image is not the current one anymore
+    fn clear_cache(&mut self);
+}
+
+/// Turns prompts into masks on an encoded image, owns its pre- and post-processing.
+pub trait Segmenter: Send {
+    /// the sessions of the model and the provider each one runs on
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)>;
+
+    /// the inputs, outputs and metadata of each session
+    fn signatures(&self) -> Vec<signature::Signature>;
+
+    /// it does not change the current image, so it can be used to encode images ahead
+    fn encode(&self, img: &image::DynamicImage) -> Result<sam::Embedding>;
+
+    /// make the embedding the current image
+    fn set_embedding(&mut self, embedding: sam::Embedding);
+
+    /// the prompts should be normalized, one list of candidates for each instance, the best first
+    fn generate_masks(&mut self, batch: Vec<Vec<Prompt>>) -> Result<Vec<Vec<sam::MaskCandidate>>>;
+
+    /// the candidate the user kept for the masks that the given prompts refine,
+    /// as its index in the list generate_masks returned, the best one by default
+    fn select_candidate(&mut self, _prompts: &[Prompt], _index: usize) {}
+
+    /// masks of everything in the current image, returns None if cancelled
+    fn segment_everything(
+        &mut self,
+        img: &image::DynamicImage,
+        config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<sam::auto::AutoMask>>>;
+
+    /// the number of instances that are decoded together
+    fn batch_size(&self) -> usize;
+}
+
+/// The detector and the segmenter chosen by the config, with the class names to use.
+pub struct Models {
+    segmenter: Box<dyn Segmenter>,
+    detector: Box<dyn Detector>,
+    class_names: Vec<String>,
+
+    embeded: bool,
+}
+
+impl Models {
+    /// Load the detector and the segmenter of the config.
+    ///
+    /// The class names of the config come first, then the ones stored in the detector.
+    pub fn new(config: &Config) -> Result<Self> {
+        let detector = Self::new_detector(config)?;
+
+        // the names in config come first, then the ones in the model
+        let class_names = if config.class_names.is_empty() {
+            detector.class_names().to_vec()
+        } else {
+            config.class_names.clone()
+        };
+
+        Ok(Self {
+            segmenter: Self::new_segmenter(config)?,
+            detector,
+            class_names,
+
+            embeded: false,
+        })
+    }
+
+    /// The providers, class names and signatures of the loaded models.
+    pub fn info(&self) -> ModelInfo {
+        let mut providers = self.segmenter.providers();
+        providers.extend(self.detector.providers());
+        let mut signatures = self.segmenter.signatures();
+        signatures.extend(self.detector.signatures());
+        ModelInfo {
+            providers,
+            class_names: self.class_names.clone(),
+            signatures,
+        }
+    }
+
+    /// Run the detector on an image, the boxes are normalized to it.
+    pub fn detect(
+        &mut self,
+        img: &image::DynamicImage,
+        params: &DetectionConfig,
+    ) -> Result<Vec<yolo::Detection>> {
+        self.detector.detect(img, params)
+    }
+
+    /// Run the detector without the postprocess, `redetect` gives the boxes.
+    pub fn infer(&mut self, img: &image::DynamicImage) -> Result<()> {
+        self.detector.infer(img)
+    }
+
+    /// Reuse the detector output of the current image with new thresholds.
+    pub fn redetect(&self, params: &DetectionConfig) -> Result<Vec<yolo::Detection>> {
+        self.detector.redetect(params)
+    }
+
+    /// Encode an image with the segmenter and make it the current one.
+    ///
+    /// The detector output of the previous image is dropped.
+    pub fn embed(&mut self, img: &image::DynamicImage) -> Result<()> {
+        self.embeded = false;
+        self.detector.clear_cache();
+        let embedding = self.segmenter.encode(img)?;
+        self.segmenter.set_embedding(embedding);
+        self.embeded = true;
+        Ok(())
+    }
+
+    /// Encode an image without changing the current one, e.g. to prefetch it.
+    pub fn encode(&self, img: &image::DynamicImage) -> Result<sam::Embedding> {
+        self.segmenter.encode(img)
+    }
+
+    /// Use an image encoded ahead as the current one.
+    pub fn set_embedding(&mut self, embedding: sam::Embedding) {
+        self.detector.clear_cache();
+        self.segmenter.set_embedding(embedding);
+        self.embeded = true;
+    }
+
+    /// Segment each instance of the current image from its normalized prompts.
+    ///
+    /// One list of candidates for each instance, the best first.
+    /// It fails if no image has been embedded.
+    pub fn generate_masks(
+        &mut self,
+        batch: Vec<Vec<Prompt>>,
+    ) -> Result<Vec<Vec<sam::MaskCandidate>>> {
+        if !self.embeded {
+            return Err(Error::Inference("no image has been embedded".into()));
+        }
+        self.segmenter.generate_masks(batch)
+    }
+
+    /// The candidate the user kept for the masks the prompts refine, fed back when they get more prompts.
+    pub fn select_candidate(&mut self, prompts: &[Prompt], index: usize) {
+        self.segmenter.select_candidate(prompts, index);
+    }
+
+    /// Masks of everything in the current image, None if cancelled.
+    pub fn segment_everything(
+        &mut self,
+        img: &image::DynamicImage,
+        config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<sam::auto::AutoMask>>> {
+        if !self.embeded {
+            return Err(Error::Inference("no image has been embedded".into()));
+        }
+        self.segmenter.segment_everything(img, config, cancelled)
+    }
+
+    /// The number of instances that go through the decoder together.
+    pub fn batch_size(&self) -> usize {
+        self.segmenter.batch_size()
+    }
+}
+
+// private
+impl Models {
+    fn new_detector(config: &Config) -> Result<Box<dyn Detector>> {
+        let path = &config.yolo_path;
+        Ok(match config.detector {
+            DetectorKind::Yolov5 => Box::new(
+                yolo::YOLOmodel::new_path(path, &config.session)?
+                    .with_layout(yolo::Layout::Objectness),
+            ),
+            DetectorKind::Yolov8 => Box::new(yolo::YOLOmodel::new_path(path, &config.session)?),
+            DetectorKind::RtDetr => Box::new(rtdetr::RTDETRmodel::new_path(path, &config.session)?),
+            DetectorKind::Mock => Box::new(mock::MockDetector::default()),
+        })
+    }
+
+    fn new_segmenter(config: &Config) -> Result<Box<dyn Segmenter>> {
+        Ok(match config.segmenter {
+            SegmenterKind::Sam => Box::new(Self::new_sam(config, None)?),
+            SegmenterKind::Sam2 => Box::new(Self::new_sam(config, Some(sam::Family::Sam2))?),
+            SegmenterKind::SamHq => Box::new(Self::new_sam(config, Some(sam::Family::SamHq))?),
+            SegmenterKind::EfficientSam => {
+                Box::new(Self::new_sam(config, Some(sam::Family::EfficientSam))?)
+            }
+            SegmenterKind::Mock => Box::new(mock::MockSegmenter::default()),
+        })
+    }
+
+    // the family is found from the encoder if it is None
+    fn new_sam(config: &Config, family: Option<sam::Family>) -> Result<sam::SAMmodel> {
+        Ok(sam::SAMmodel::new_path(
+            &config.sam_e_path,
+            &config.sam_d_path,
+            &config.session,
+            family,
+        )?
+        .with_cache(sam::cache::EmbeddingCache::new(
+            &config.cache,
+            &config.sam_e_path,
+        )))
+    }
+}
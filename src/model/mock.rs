@@ -0,0 +1,190 @@
+//! Models without model files, for tests and for trying the ui.
+
+use super::sam::{auto::AutoMask, prompt::Prompt, Embedding, MaskCandidate};
+use super::signature::Signature;
+use super::yolo::{BoundingBox, Detection};
+use super::{Detector, Segmenter, SessionProvider};
+use crate::config::{AutoMaskConfig, DetectionConfig};
+use crate::error::{Error, Result};
+
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+// half the side of the square a point prompt gives
+const POINT_RADIUS: f32 = 0.05;
+
+// the same boxes for every image, normalized
+const BOXES: [([f32; 4], f32, usize); 2] = [
+    ([0.1, 0.1, 0.4, 0.5], 0.9, 0),
+    ([0.5, 0.4, 0.9, 0.8], 0.6, 1),
+];
+
+/// A detector without a model file, for testing the ui and the worker.
+#[derive(Debug, Default)]
+pub struct MockDetector {
+    class_names: Vec<String>,
+    detected: bool, // whether there is an image to re-detect
+}
+
+/// A segmenter without a model file, the mask of the prompts is the rectangle around them.
+#[derive(Debug, Default)]
+pub struct MockSegmenter {
+    size: Option<(u32, u32)>, // of the current image
+}
+
+impl MockDetector {
+    fn detections(params: &DetectionConfig) -> Vec<Detection> {
+        let mut detections: Vec<Detection> = BOXES
+            .iter()
+            .filter(|(_, conf, _)| *conf >= params.conf_threshold)
+            .map(|&([x1, y1, x2, y2], conf, class_id)| Detection {
+                bbox: BoundingBox::new(x1, y1, x2, y2),
+                conf,
+                class_id,
+                mask: None,
+            })
+            .collect();
+        if params.max_detections > 0 {
+            detections.truncate(params.max_detections);
+        }
+
+        detections
+    }
+}
+
+impl Detector for MockDetector {
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)> {
+        Vec::new()
+    }
+
+    fn signatures(&self) -> Vec<Signature> {
+        Vec::new()
+    }
+
+    fn class_names(&self) -> &[String] {
+        &self.class_names
+    }
+
+    fn infer(&mut self, _img: &DynamicImage) -> Result<()> {
+        self.detected = true;
+        Ok(())
+    }
+
+    fn redetect(&self, params: &DetectionConfig) -> Result<Vec<Detection>> {
+        if !self.detected {
+            return Err(Error::Inference(
+                "Nothing to re-detect, run Detect first".into(),
+            ));
+        }
+        Ok(Self::detections(params))
+    }
+
+    fn clear_cache(&mut self) {
+        self.detected = false;
+    }
+}
+
+impl MockSegmenter {
+    // filled rectangle in normalized coordinates
+    fn rect_mask(&self, rect: Option<[f32; 4]>) -> Result<DynamicImage> {
+        let (w, h) = self
+            .size
+            .ok_or_else(|| Error::Inference("no image has been embedded".into()))?;
+
+        let mut mask = GrayImage::new(w, h);
+        if let Some([x1, y1, x2, y2]) = rect {
+            for (x, y, p) in mask.enumerate_pixels_mut() {
+                let (cx, cy) = ((x as f32 + 0.5) / w as f32, (y as f32 + 0.5) / h as f32);
+                if cx >= x1 && cx <= x2 && cy >= y1 && cy <= y2 {
+                    p.0[0] = 255;
+                }
+            }
+        }
+
+        Ok(DynamicImage::ImageLuma8(mask))
+    }
+
+    // the rectangle around the boxes and the positive points, None without them
+    fn extent(prompts: &[Prompt]) -> Option<[f32; 4]> {
+        prompts
+            .iter()
+            .filter_map(|prompt| match *prompt {
+                Prompt::Point(([x, y], label)) if label > 0.0 => Some([
+                    x - POINT_RADIUS,
+                    y - POINT_RADIUS,
+                    x + POINT_RADIUS,
+                    y + POINT_RADIUS,
+                ]),
+                Prompt::Point(_) => None,
+                Prompt::Box(bbox) => Some(bbox),
+            })
+            .reduce(|a, b| {
+                [
+                    a[0].min(b[0]),
+                    a[1].min(b[1]),
+                    a[2].max(b[2]),
+                    a[3].max(b[3]),
+                ]
+            })
+    }
+}
+
+impl Segmenter for MockSegmenter {
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)> {
+        Vec::new()
+    }
+
+    fn signatures(&self) -> Vec<Signature> {
+        Vec::new()
+    }
+
+    fn encode(&self, img: &DynamicImage) -> Result<Embedding> {
+        let (w, h) = img.dimensions();
+        Ok(Embedding::empty(w, h))
+    }
+
+    fn set_embedding(&mut self, embedding: Embedding) {
+        self.size = Some(embedding.size());
+    }
+
+    fn generate_masks(&mut self, batch: Vec<Vec<Prompt>>) -> Result<Vec<Vec<MaskCandidate>>> {
+        batch
+            .iter()
+            .map(|prompts| {
+                Ok(vec![MaskCandidate {
+                    mask: self.rect_mask(Self::extent(prompts))?,
+                    iou: 1.0,
+                    stability: 1.0,
+                }])
+            })
+            .collect()
+    }
+
+    // one object in each quarter of the image
+    fn segment_everything(
+        &mut self,
+        _img: &DynamicImage,
+        _config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<AutoMask>>> {
+        let mut masks = Vec::new();
+        for [x, y] in [[0.0, 0.0], [0.5, 0.0], [0.0, 0.5], [0.5, 0.5]] {
+            if cancelled() {
+                return Ok(None);
+            }
+            masks.push(AutoMask {
+                point: [x + 0.25, y + 0.25],
+                candidate: MaskCandidate {
+                    mask: self.rect_mask(Some([x + 0.05, y + 0.05, x + 0.45, y + 0.45]))?,
+                    iou: 1.0,
+                    stability: 1.0,
+                },
+            });
+        }
+
+        Ok(Some(masks))
+    }
+
+    fn batch_size(&self) -> usize {
+        4
+    }
+}
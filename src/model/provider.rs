@@ -0,0 +1,83 @@
+use crate::config::{Provider, SessionConfig};
+use crate::error::{Error, Result};
+
+use std::fmt;
+
+use ort::{
+    CPUExecutionProvider, CUDAExecutionProvider, CoreMLExecutionProvider,
+    DirectMLExecutionProvider, ExecutionProviderDispatch, GraphOptimizationLevel,
+    OpenVINOExecutionProvider, Session, TensorRTExecutionProvider,
+};
+
+impl Provider {
+    fn dispatch(&self) -> ExecutionProviderDispatch {
+        match self {
+            Provider::Cpu => CPUExecutionProvider::default()
+                .with_arena_allocator()
+                .build(),
+            Provider::Cuda => CUDAExecutionProvider::default().build(),
+            Provider::TensorRT => TensorRTExecutionProvider::default().build(),
+            Provider::OpenVINO => OpenVINOExecutionProvider::default().build(),
+            Provider::DirectML => DirectMLExecutionProvider::default().build(),
+            Provider::CoreML => CoreMLExecutionProvider::default().build(),
+        }
+    }
+}
+
+/// The execution provider a session runs on, with the ones tried before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionProvider {
+    /// The provider the session runs on.
+    pub provider: Provider,
+    /// The providers that could not load the model first, with the reason.
+    pub failed: Vec<(Provider, String)>,
+}
+
+impl fmt::Display for SessionProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.provider)
+    }
+}
+
+// try the providers in the configured order and keep the first session that can be created,
+// the cpu provider is always the last resort
+// returns the session together with the provider it actually runs on and why the others failed
+pub fn build_session(path: &str, config: &SessionConfig) -> Result<(Session, SessionProvider)> {
+    let mut providers = config.providers.clone();
+    if !providers.contains(&Provider::Cpu) {
+        providers.push(Provider::Cpu);
+    }
+
+    if !std::path::Path::new(path).is_file() {
+        return Err(Error::ModelLoad(
+            path.to_string(),
+            ort::Error::new("model file does not exist"),
+        ));
+    }
+
+    let mut failed = Vec::new();
+    let mut last_err = None;
+    for provider in providers {
+        match try_build(path, config, provider) {
+            Ok(session) => return Ok((session, SessionProvider { provider, failed })),
+            Err(e) => {
+                // the ui shows it next to the provider, batch mode only has the terminal
+                eprintln!("Failed to load {path} with {provider}: {e}");
+                failed.push((provider, e.to_string()));
+                last_err = Some(e);
+            }
+        }
+    }
+
+    // cpu is always tried, so there is at least one error here
+    Err(Error::ModelLoad(path.to_string(), last_err.unwrap()))
+}
+
+fn try_build(path: &str, config: &SessionConfig, provider: Provider) -> ort::Result<Session> {
+    Session::builder()?
+        .with_optimization_level(GraphOptimizationLevel::Level3)?
+        .with_execution_providers([provider.dispatch().error_on_failure()])?
+        .with_intra_threads(config.intra_threads)?
+        .with_inter_threads(config.inter_threads)?
+        .commit_from_file(path)
+}
@@ -0,0 +1,195 @@
+//! RT-DETR detection exports.
+
+use image::{imageops::FilterType, GenericImageView};
+use ndarray::{Array, ArrayD, ArrayViewD, Axis, Dim};
+use ort::{inputs, Session, SessionOutputs};
+
+use super::signature::{self, Signature, Spec};
+use super::yolo::{metadata_names, BoundingBox, Detection};
+use super::{provider, Detector, SessionProvider};
+use crate::config::{DetectionConfig, SessionConfig};
+use crate::error::{Error, Result};
+
+// the ultralytics export takes a fixed input like yolo
+const INPUT_H: u32 = 640;
+const INPUT_W: u32 = 640;
+
+const INPUTS: [Spec; 1] = [Spec::f32(&["images"], &[1, 3, 640, 640])];
+const OUTPUTS: [Spec; 1] = [Spec::f32(&["output0"], &[1, -1, -1])];
+
+/// RT-DETR exported by ultralytics, it gives a fixed number of queries and needs no nms.
+#[derive(Debug)]
+pub struct RTDETRmodel {
+    model: Session,
+    provider: SessionProvider,
+    class_names: Vec<String>,
+    signature: Signature,
+
+    // raw output of the last image, so that thresholds can be changed without running the model
+    cache: Option<ArrayD<f32>>,
+}
+
+impl RTDETRmodel {
+    /// Load an export, the class names are read from its metadata.
+    pub fn new_path(p: &str, config: &SessionConfig) -> Result<Self> {
+        let (model, provider) = provider::build_session(p, config)?;
+        signature::check(p, &model, &INPUTS, &OUTPUTS)?;
+        let class_names = metadata_names(&model);
+        let signature = Signature::of("RT-DETR", p, &model);
+
+        Ok(Self {
+            model,
+            provider,
+            class_names,
+            signature,
+            cache: None,
+        })
+    }
+
+    // the image is stretched to the input without keeping the aspect ratio,
+    // so the normalized boxes of the output are normalized to the image too
+    fn preprocess(img: &image::DynamicImage) -> Array<f32, Dim<[usize; 4]>> {
+        let img = img.resize_exact(INPUT_W, INPUT_H, FilterType::CatmullRom);
+        let mut input = Array::zeros((1, 3, INPUT_H as usize, INPUT_W as usize));
+        for (x, y, pixel) in img.pixels() {
+            let (x, y) = (x as usize, y as usize);
+            let [r, g, b, _] = pixel.0;
+
+            input[[0, 0, y, x]] = (r as f32) / 255.0;
+            input[[0, 1, y, x]] = (g as f32) / 255.0;
+            input[[0, 2, y, x]] = (b as f32) / 255.0;
+        }
+
+        input
+    }
+
+    // output is (1, num_queries, 4 + num_classes), the boxes are normalized xc, yc, w, h
+    fn postprocess(output: ArrayViewD<f32>, params: &DetectionConfig) -> Result<Vec<Detection>> {
+        if output.ndim() != 3 || output.shape()[2] <= 4 {
+            return Err(Error::Inference(format!(
+                "unexpected RT-DETR output shape: {:?}",
+                output.shape()
+            )));
+        }
+
+        let mut detections = Vec::new();
+        for query in output.index_axis(Axis(0), 0).axis_iter(Axis(0)) {
+            let row: Vec<_> = query.iter().copied().collect();
+            let (class_id, conf) = row[4..]
+                .iter()
+                .copied()
+                .enumerate()
+                .reduce(|accum, one| if one.1 > accum.1 { one } else { accum })
+                .unwrap_or((0, 0.0));
+            if conf < params.conf_threshold {
+                continue;
+            }
+
+            let (xc, yc, w, h) = (row[0], row[1], row[2], row[3]);
+            let bbox = BoundingBox::new(
+                (xc - w / 2.0).clamp(0.0, 1.0),
+                (yc - h / 2.0).clamp(0.0, 1.0),
+                (xc + w / 2.0).clamp(0.0, 1.0),
+                (yc + h / 2.0).clamp(0.0, 1.0),
+            );
+            // outside the image or without a size
+            if !bbox.has_area() {
+                continue;
+            }
+            detections.push(Detection {
+                bbox,
+                conf,
+                class_id,
+                mask: None,
+            });
+        }
+
+        detections.sort_by(|a, b| b.conf.total_cmp(&a.conf));
+        if params.max_detections > 0 {
+            detections.truncate(params.max_detections);
+        }
+
+        Ok(detections)
+    }
+}
+
+impl Detector for RTDETRmodel {
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)> {
+        vec![("RT-DETR", self.provider.clone())]
+    }
+
+    fn signatures(&self) -> Vec<Signature> {
+        vec![self.signature.clone()]
+    }
+
+    fn class_names(&self) -> &[String] {
+        &self.class_names
+    }
+
+    fn infer(&mut self, img: &image::DynamicImage) -> Result<()> {
+        let input = Self::preprocess(img);
+
+        let outputs: SessionOutputs = self.model.run(inputs!["images" => input.view()]?)?;
+        let output = outputs
+            .get("output0")
+            .ok_or_else(|| Error::Inference("RT-DETR model has no output output0".into()))?
+            .try_extract_tensor::<f32>()?
+            .into_owned();
+
+        self.cache = Some(output);
+        Ok(())
+    }
+
+    fn redetect(&self, params: &DetectionConfig) -> Result<Vec<Detection>> {
+        match &self.cache {
+            Some(output) => Self::postprocess(output.view(), params),
+            None => Err(Error::Inference(
+                "Nothing to re-detect, run Detect first".into(),
+            )),
+        }
+    }
+
+    fn clear_cache(&mut self) {
+        self.cache = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    #[test]
+    fn postprocess_keeps_confident_queries() {
+        let mut output = Array3::<f32>::zeros((1, 4, 6));
+        for (i, values) in [
+            [0.5, 0.5, 0.2, 0.4, 0.1, 0.7],
+            [0.1, 0.1, 0.4, 0.4, 0.9, 0.2], // partly outside the image
+            [0.5, 0.5, 0.1, 0.1, 0.2, 0.3], // below threshold
+            [1.5, 0.5, 0.2, 0.2, 0.8, 0.1], // outside the image
+        ]
+        .iter()
+        .enumerate()
+        {
+            for (j, v) in values.iter().enumerate() {
+                output[[0, i, j]] = *v;
+            }
+        }
+
+        let params = DetectionConfig::default();
+        let detections = RTDETRmodel::postprocess(output.view().into_dyn(), &params).unwrap();
+        let confs: Vec<f32> = detections.iter().map(|d| d.conf).collect();
+        assert_eq!(confs, vec![0.9, 0.7]);
+        assert_eq!(detections[0].class_id, 0);
+        assert_eq!(detections[0].bbox.x1, 0.0);
+        assert!((detections[0].bbox.x2 - 0.3).abs() < 1e-6);
+        assert_eq!(detections[1].class_id, 1);
+
+        let params = DetectionConfig {
+            max_detections: 1,
+            ..Default::default()
+        };
+        let detections = RTDETRmodel::postprocess(output.view().into_dyn(), &params).unwrap();
+        assert_eq!(detections.len(), 1);
+    }
+}
@@ -0,0 +1,1039 @@
+//! SAM, MobileSAM, SAM2, SAM-HQ and EfficientSAM exports as an encoder and a decoder.
+
+pub mod auto;
+pub mod cache;
+pub mod prompt;
+pub mod transform;
+
+use cache::EmbeddingCache;
+use prompt::Prompt;
+use transform::ResizeLongestSide;
+
+use ndarray::{s, Array1, Array2, Array3, Array4, ArrayD, ArrayView4, ArrayViewD, Axis};
+use ort::{inputs, Session, TensorElementType, ValueType};
+
+use super::signature::{self, Signature, Spec};
+use super::{provider, Segmenter, SessionProvider};
+use crate::config::{AutoMaskConfig, SessionConfig};
+use crate::error::{Error, Result};
+
+use image::{DynamicImage, GenericImageView};
+
+// the number of previous masks kept for refinement
+const LOGITS_LIMIT: usize = 64;
+
+// the most instances in one decoder call
+const MAX_BATCH: usize = 16;
+
+/// The (n, c, h, w) shape of an embedding.
+pub type Shape = (usize, usize, usize, usize);
+
+// the image, HWC or NCHW, the names differ between exports
+const ENCODER_INPUTS: [Spec; 1] = [Spec::f32(
+    &[
+        "input_image",
+        "image",
+        "images",
+        "input",
+        "x",
+        EFFICIENT_SAM_IMAGE,
+    ],
+    &[],
+)
+.any_rank()];
+// EfficientSAM encoders take the image at its size in [0, 1] and resize it themselves
+const EFFICIENT_SAM_IMAGE: &str = "batched_images";
+
+// the encoder outputs the decoder takes with the same names, the image embedding first
+// the batch is one image for the encoder and dynamic for batched decoders
+const SAM_EMBEDDING: [Spec; 1] = [Spec::f32(&["image_embeddings"], &[-1, 256, 64, 64])];
+const SAM2_EMBEDDING: [Spec; 3] = [
+    Spec::f32(&["image_embed"], &[-1, 256, 64, 64]),
+    Spec::f32(&["high_res_feats_0"], &[-1, 32, 256, 256]),
+    Spec::f32(&["high_res_feats_1"], &[-1, 64, 128, 128]),
+];
+// the features of the early and late layers of the ViT, (layers, 1, 64, 64, width of the ViT)
+const SAM_HQ_EMBEDDING: [Spec; 2] = [
+    Spec::f32(&["image_embeddings"], &[-1, 256, 64, 64]),
+    Spec::f32(&["interm_embeddings"], &[-1, 1, 64, 64, -1]),
+];
+
+// the decoder inputs besides the embedding, the first dimensions are dynamic for batches
+const PROMPT_INPUTS: [Spec; 4] = [
+    Spec::f32(&["point_coords"], &[-1, -1, 2]),
+    Spec::f32(&["point_labels"], &[-1, -1]),
+    Spec::f32(&["mask_input"], &[-1, 1, 256, 256]),
+    Spec::f32(&["has_mask_input"], &[-1]),
+];
+
+// EfficientSAM takes the points in pixels of the original image with a query dimension of 1,
+// and has no mask input
+const EFFICIENT_SAM_PROMPT_INPUTS: [Spec; 2] = [
+    Spec::f32(&["batched_point_coords"], &[-1, 1, -1, 2]),
+    Spec::f32(&["batched_point_labels"], &[-1, 1, -1]),
+];
+
+// the decoder input of the original image size as (h, w),
+// SAM2 exports have it as int32 or return the low-res masks without it,
+// EfficientSAM exports have it as int64
+const ORIG_SIZE_INPUT: &str = "orig_im_size";
+const SAM_ORIG_SIZE: Spec = Spec::f32(&[ORIG_SIZE_INPUT], &[2]);
+const ANY_ORIG_SIZE: Spec = Spec::f32(&[ORIG_SIZE_INPUT], &[2]).any_type().optional();
+
+// the masks, then the ious, then the low-res logits
+const DECODER_OUTPUTS: [Spec; 3] = [
+    Spec::f32(&["masks"], &[-1, -1, -1, -1]),
+    Spec::f32(&["iou_predictions"], &[-1, -1]).optional(), // some exports only have masks
+    Spec::f32(&["low_res_masks"], &[-1, -1, 256, 256]).optional(),
+];
+// (b, 1, 3, h, w) masks in the original size and (b, 1, 3) ious
+const EFFICIENT_SAM_OUTPUTS: [Spec; 2] = [
+    Spec::f32(&["output_masks"], &[-1, 1, -1, -1, -1]),
+    Spec::f32(&["iou_predictions"], &[-1, 1, -1]).optional(),
+];
+
+// the logits above it are in the mask
+const MASK_THRESHOLD: f32 = 0.5;
+// the stability score compares the masks thresholded at MASK_THRESHOLD +/- STABILITY_OFFSET
+const STABILITY_OFFSET: f32 = 1.0;
+
+// the masks decoded for the prompts of an instance, to refine the selected one
+#[derive(Debug)]
+struct PreviousMasks {
+    prompts: Vec<Prompt>,
+    logits: Vec<Array4<f32>>, // (1, 1, 256, 256) each, in the order of the returned candidates
+    selected: usize,          // the best one unless the user picked another
+}
+
+#[derive(Debug, Default)]
+struct MaskHistory(Vec<PreviousMasks>);
+
+// the prompt tensors of a batch for the decoder
+#[derive(Debug)]
+struct DecoderPrompts {
+    points: Array3<f32>,         // (b, n, 2)
+    labels: Array2<f32>,         // (b, n)
+    mask_input: Array4<f32>,     // (b, 1, 256, 256)
+    has_mask_input: Array1<f32>, // (b)
+}
+
+/// The exports SAMmodel can run, they differ in the encoder outputs and the decoder inputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// SAM and MobileSAM.
+    Sam,
+    /// SAM2 and SAM2.1, with the high-res features of the hiera encoder.
+    Sam2,
+    /// SAM-HQ, with the intermediate features of the ViT.
+    SamHq,
+    /// EfficientSAM, whose encoder resizes the image and whose decoder takes pixels.
+    EfficientSam,
+}
+
+/// The output of the encoder for an image, ready for the decoder.
+#[derive(Debug, Clone)]
+pub struct Embedding {
+    data: Vec<Array4<f32>>, // in the order of the family's encoder outputs, see embedding_shape
+    transform: ResizeLongestSide,
+}
+
+/// One of the masks the decoder gives for the same prompts.
+#[derive(Debug, Clone)]
+pub struct MaskCandidate {
+    /// The mask at the size of the original image, 255 inside the object.
+    pub mask: DynamicImage,
+    /// The IoU predicted by the decoder.
+    pub iou: f32,
+    /// How much the mask changes with the threshold, 1.0 for not at all.
+    pub stability: f32,
+}
+
+/// A SAM, MobileSAM, SAM2, SAM-HQ or EfficientSAM export as an encoder and a decoder session.
+///
+/// `embed` encodes an image, then `generate_mask` turns normalized prompts into mask candidates,
+/// the best first.
+#[derive(Debug)]
+pub struct SAMmodel {
+    encoder: Session,
+    decoder: Session,
+    encoder_provider: SessionProvider,
+    decoder_provider: SessionProvider,
+    family: Family,
+    encoder_input: String,
+    shapes: Vec<Shape>, // of the encoder outputs for one image
+    signatures: Vec<Signature>,
+
+    embedding: Option<Vec<Array4<f32>>>,
+    transform: ResizeLongestSide,
+    cache: Option<EmbeddingCache>,
+    batched: bool, // whether the decoder takes more than one instance at once
+
+    // the low-res logits of the masks of the last prompts of each instance,
+    // the selected one is fed back when the instance gets more prompts
+    history: MaskHistory,
+}
+
+impl SAMmodel {
+    /// Load `weights/sam_b-encoder.onnx` and `weights/sam_b-decoder.onnx`
+    /// with the default session options.
+    pub fn new() -> Result<Self> {
+        Self::new_path(
+            "weights/sam_b-encoder.onnx",
+            "weights/sam_b-decoder.onnx",
+            &SessionConfig::default(),
+            None,
+        )
+    }
+
+    /// The family is found from the encoder inputs and outputs if it is None.
+    pub fn new_path(
+        encoder_path: &str,
+        decoder_path: &str,
+        config: &SessionConfig,
+        family: Option<Family>,
+    ) -> Result<Self> {
+        let (encoder, encoder_provider) = provider::build_session(encoder_path, config)?;
+        let (decoder, decoder_provider) = provider::build_session(decoder_path, config)?;
+        let family = family.unwrap_or_else(|| Family::of(&encoder));
+
+        signature::check(encoder_path, &encoder, &ENCODER_INPUTS, family.embedding())?;
+        signature::check(
+            decoder_path,
+            &decoder,
+            &family.decoder_inputs(),
+            family.decoder_outputs(),
+        )?;
+        let encoder_input = signature::find(&encoder, &ENCODER_INPUTS[0]).unwrap_or_default();
+        let shapes = family
+            .embedding()
+            .iter()
+            .map(|spec| {
+                let dims = encoder
+                    .outputs
+                    .iter()
+                    .find(|output| spec.names.contains(&output.name.as_str()))
+                    .and_then(|output| output.output_type.tensor_dimensions())
+                    .map_or(&[][..], |dims| dims.as_slice());
+                Self::embedding_shape(spec, dims).ok_or_else(|| {
+                    Error::Signature(
+                        encoder_path.to_string(),
+                        format!("output `{}` needs fixed sizes", spec.names[0]),
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        let (encoder_name, decoder_name) = family.names();
+        let signatures = vec![
+            Signature::of(encoder_name, encoder_path, &encoder),
+            Signature::of(decoder_name, decoder_path, &decoder),
+        ];
+        let batched = Self::is_batched(&decoder);
+
+        Ok(Self {
+            encoder,
+            decoder,
+            encoder_provider,
+            decoder_provider,
+            family,
+            encoder_input,
+            shapes,
+            signatures,
+            embedding: None,
+            transform: ResizeLongestSide::default(),
+            cache: None,
+            batched,
+            history: MaskHistory::default(),
+        })
+    }
+
+    /// Reuse and store the embeddings of images encoded before.
+    pub fn with_cache(mut self, cache: Option<EmbeddingCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Embed the image and return the best mask of one prompt.
+    pub fn forward(&mut self, img: &DynamicImage, prompt: Prompt) -> Result<DynamicImage> {
+        self.embed(img)?;
+        let mut candidates = self.generate_mask(vec![prompt])?;
+        Ok(candidates.remove(0).mask)
+    }
+
+    /// Encode an image and make it the current one, the prompts of the decoder refer to it.
+    pub fn embed(&mut self, img: &DynamicImage) -> Result<()> {
+        self.embedding = None;
+        let embedding = self.encode(img)?;
+        self.set_embedding(embedding);
+        Ok(())
+    }
+
+    /// Encode an image without changing the current one, so it can be used to encode images ahead.
+    ///
+    /// The embedding is loaded from the cache if the image has been encoded before.
+    pub fn encode(&self, img: &DynamicImage) -> Result<Embedding> {
+        let (ori_w, ori_h) = img.dimensions();
+        let transform = self.family.transform(ori_w, ori_h);
+        let outputs = self.family.embedding();
+        let shapes = self.shapes.clone();
+
+        let key = self.cache.as_ref().map(|cache| cache.key(img));
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            if let Some(data) = cache.load(key, &shapes) {
+                return Ok(Embedding { data, transform });
+            }
+        }
+
+        let input = match self.family {
+            Family::EfficientSam => Self::unscaled_image(img),
+            _ => self.preprocess_img(img, &transform),
+        };
+        let encoder_input = inputs!(self.encoder_input.as_str() => input.view())?;
+        let mut encoder_output = self.encoder.run(encoder_input)?;
+        let mut data = Vec::new();
+        for (spec, shape) in outputs.iter().zip(shapes) {
+            let name = spec.names[0];
+            let output = encoder_output
+                .remove(name)
+                .ok_or_else(|| Error::Inference(format!("encoder has no output {name}")))?;
+            data.push(
+                output
+                    .try_extract_tensor::<f32>()?
+                    .to_shape(shape)?
+                    .to_owned(),
+            );
+        }
+
+        if let (Some(cache), Some(key)) = (&self.cache, &key) {
+            cache.store(key, &data);
+        }
+
+        Ok(Embedding { data, transform })
+    }
+
+    /// Make the embedding the current image, the logits of the previous one are dropped.
+    pub fn set_embedding(&mut self, embedding: Embedding) {
+        self.transform = embedding.transform;
+        self.embedding = Some(embedding.data);
+        self.history.clear();
+    }
+
+    /// Segment one instance of the current image from its normalized prompts.
+    ///
+    /// Returns all the candidates from the decoder, the best one first.
+    /// The logits of the selected candidate of the same prompts without the last ones are fed back.
+    pub fn generate_mask(&mut self, prompts: Vec<Prompt>) -> Result<Vec<MaskCandidate>> {
+        Ok(self.decode(&[prompts])?.remove(0))
+    }
+
+    /// The same as `generate_mask` for several instances, one list of candidates for each.
+    ///
+    /// They go through the decoder together if it takes a batch, otherwise one by one.
+    pub fn generate_masks(&mut self, batch: Vec<Vec<Prompt>>) -> Result<Vec<Vec<MaskCandidate>>> {
+        let mut results = Vec::new();
+        for chunk in batch.chunks(self.batch_size()) {
+            results.extend(self.decode(chunk)?);
+        }
+
+        Ok(results)
+    }
+
+    /// The candidate, in the order `generate_mask` returned them, whose logits are fed back
+    /// when the prompts it was decoded from get more prompts.
+    pub fn select_candidate(&mut self, prompts: &[Prompt], index: usize) {
+        self.history.select(prompts, index);
+    }
+
+    /// The number of instances the decoder takes at once.
+    pub fn batch_size(&self) -> usize {
+        if self.batched {
+            MAX_BATCH
+        } else {
+            1
+        }
+    }
+
+    fn decode(&mut self, batch: &[Vec<Prompt>]) -> Result<Vec<Vec<MaskCandidate>>> {
+        let b = batch.len();
+        let mut prompts = Vec::new();
+        for one in batch {
+            prompts.push(self.preprocess_prompts(one.clone())?);
+        }
+        let previous: Vec<_> = batch.iter().map(|one| self.history.previous(one)).collect();
+        let DecoderPrompts {
+            points,
+            labels,
+            mask_input,
+            has_mask_input,
+        } = DecoderPrompts::new(&prompts, &previous);
+
+        // the decoder takes the size as (h, w)
+        let orig_size = Array1::from(vec![
+            self.transform.ori_h as f32,
+            self.transform.ori_w as f32,
+        ]);
+        let orig_size_int = orig_size.mapv(|v| v as i32);
+        let orig_size_long = orig_size.mapv(|v| v as i64);
+        let embedding = self
+            .embedding
+            .as_ref()
+            .ok_or_else(|| Error::Inference("no image has been embedded".into()))?;
+        // the same image for every instance
+        let mut emb = Vec::new();
+        for data in embedding {
+            emb.push(if b == 1 {
+                data.clone()
+            } else {
+                let (_, c, h, w) = data.dim();
+                data.broadcast((b, c, h, w))
+                    .ok_or_else(|| Error::Inference("failed to batch the embedding".into()))?
+                    .as_standard_layout()
+                    .into_owned()
+            });
+        }
+        if emb.len() != self.family.embedding().len() {
+            return Err(Error::Inference(
+                "the embedding does not belong to the segmenter".into(),
+            ));
+        }
+
+        // every tensor by name, the embedding has the names of the encoder outputs
+        let mut decoder_input = match self.family {
+            Family::EfficientSam => inputs!(
+                EFFICIENT_SAM_PROMPT_INPUTS[0].names[0] => points.insert_axis(Axis(1)).view(),
+                EFFICIENT_SAM_PROMPT_INPUTS[1].names[0] => labels.insert_axis(Axis(1)).view(),
+            )?,
+            _ => inputs!(
+                PROMPT_INPUTS[0].names[0] => points.view(),
+                PROMPT_INPUTS[1].names[0] => labels.view(),
+                PROMPT_INPUTS[2].names[0] => mask_input.view(),
+                PROMPT_INPUTS[3].names[0] => has_mask_input.view(),
+            )?,
+        };
+        for (spec, data) in self.family.embedding().iter().zip(&emb) {
+            let data = Self::decoder_tensor(spec, data.view());
+            decoder_input.extend(inputs!(spec.names[0] => data)?);
+        }
+        let orig_size_type = self
+            .decoder
+            .inputs
+            .iter()
+            .find(|input| input.name == ORIG_SIZE_INPUT)
+            .map(|input| &input.input_type);
+        match orig_size_type {
+            Some(ValueType::Tensor {
+                ty: TensorElementType::Int32,
+                ..
+            }) => decoder_input.extend(inputs!(ORIG_SIZE_INPUT => orig_size_int.view())?),
+            Some(ValueType::Tensor {
+                ty: TensorElementType::Int64,
+                ..
+            }) => decoder_input.extend(inputs!(ORIG_SIZE_INPUT => orig_size_long.view())?),
+            Some(_) => decoder_input.extend(inputs!(ORIG_SIZE_INPUT => orig_size.view())?),
+            None => (),
+        }
+        let decoder_output = self.decoder.run(decoder_input)?;
+        let outputs = self.family.decoder_outputs();
+        let masks = decoder_output
+            .get(outputs[0].names[0])
+            .ok_or_else(|| Error::Inference("decoder has no output masks".into()))?
+            .try_extract_tensor::<f32>()?;
+        if masks.shape().first() != Some(&b) {
+            return Err(Error::Inference(format!(
+                "unexpected mask shape from decoder: {:?}",
+                masks.shape()
+            )));
+        }
+        let ious = match decoder_output.get(outputs[1].names[0]) {
+            Some(ious) => Some(ious.try_extract_tensor::<f32>()?),
+            None => None,
+        };
+        let low_res = match decoder_output.get("low_res_masks") {
+            Some(low_res) => Some(low_res.try_extract_tensor::<f32>()?),
+            None => None,
+        };
+
+        let mut results = Vec::new();
+        let mut kept = Vec::new();
+        for (i, one) in batch.iter().enumerate() {
+            let ious = ious
+                .as_ref()
+                .filter(|ious| ious.shape().first() == Some(&b));
+            let candidates = Self::postprocess(
+                Self::item(&masks, i),
+                ious.map(|ious| Self::item(ious, i)),
+                &self.transform,
+            )?;
+
+            // the best first, the logits of every candidate are kept in the same order
+            let mut order: Vec<usize> = (0..candidates.len()).collect();
+            order.sort_by(|&a, &b| candidates[b].score().total_cmp(&candidates[a].score()));
+            if let Some(low_res) = low_res.as_ref().filter(|l| l.shape().first() == Some(&b)) {
+                let logits: Option<Vec<_>> = order
+                    .iter()
+                    .map(|&j| Self::low_res_logits(Self::item(low_res, i), j))
+                    .collect();
+                if let Some(logits) = logits {
+                    kept.push((one.clone(), logits));
+                }
+            }
+
+            let mut candidates: Vec<_> = candidates.into_iter().map(Some).collect();
+            results.push(order.iter().filter_map(|&j| candidates[j].take()).collect());
+        }
+
+        drop(decoder_output);
+        for (prompts, logits) in kept {
+            self.history.keep(prompts, logits);
+        }
+
+        Ok(results)
+    }
+
+    // the i-th item of a batch, still with the batch dimension of 1
+    fn item<'a>(arr: &'a ArrayViewD<f32>, i: usize) -> ArrayViewD<'a, f32> {
+        arr.index_axis(Axis(0), i).insert_axis(Axis(0))
+    }
+
+    // every input except orig_size needs a dynamic first dimension,
+    // so not SAM-HQ decoders, whose intermediate embeddings have the layers first
+    fn is_batched(decoder: &Session) -> bool {
+        decoder
+            .inputs
+            .iter()
+            .filter(|input| input.name != ORIG_SIZE_INPUT)
+            .all(|input| {
+                input
+                    .input_type
+                    .tensor_dimensions()
+                    .is_some_and(|dims| dims.first() == Some(&-1))
+            })
+    }
+
+    // the shape of an encoder output for one image, the sizes the spec leaves open come from
+    // the dimensions of the model, None if they are dynamic there too.
+    // the 5D intermediate embeddings of SAM-HQ are kept without their batch dimension of 1
+    fn embedding_shape(spec: &Spec, dims: &[i64]) -> Option<Shape> {
+        let expected = spec.shape?;
+        let size = |i: usize| {
+            [expected.get(i), dims.get(i)]
+                .into_iter()
+                .flatten()
+                .find(|&&d| d > 0)
+                .map(|&d| d as usize)
+        };
+        match expected.len() {
+            4 => Some((1, size(1)?, size(2)?, size(3)?)),
+            5 => Some((size(0)?, size(2)?, size(3)?, size(4)?)),
+            _ => None,
+        }
+    }
+
+    // the embedding as the decoder takes it, with the batch dimension of SAM-HQ back
+    fn decoder_tensor<'a>(spec: &Spec, data: ArrayView4<'a, f32>) -> ArrayViewD<'a, f32> {
+        match spec.shape.map(<[i64]>::len) {
+            Some(5) => data.insert_axis(Axis(1)).into_dyn(),
+            _ => data.into_dyn(),
+        }
+    }
+
+    // NCHW in [0, 1] at the original size, for encoders that resize and normalize themselves
+    fn unscaled_image(img: &DynamicImage) -> ArrayD<f32> {
+        let img = img.to_rgb8();
+        let (w, h) = img.dimensions();
+        Array4::from_shape_fn((1, 3, h as usize, w as usize), |(_, c, y, x)| {
+            img.get_pixel(x as u32, y as u32).0[c] as f32 / 255.0
+        })
+        .into_dyn()
+    }
+
+    // the low-res masks are (1, n, 256, 256), take the i-th one as (1, 1, 256, 256)
+    fn low_res_logits(low_res: ArrayViewD<f32>, i: usize) -> Option<Array4<f32>> {
+        let shape = low_res.shape();
+        if shape.len() != 4 || shape[2] != 256 || shape[3] != 256 || i >= shape[1] {
+            return None;
+        }
+
+        low_res
+            .index_axis(Axis(1), i)
+            .insert_axis(Axis(1))
+            .into_dimensionality()
+            .ok()
+            .map(|a| a.to_owned())
+    }
+
+    // the encoder takes either a HWC image or a NCHW batch, decided by the rank of its input
+    fn preprocess_img(&self, img: &DynamicImage, transform: &ResizeLongestSide) -> ArrayD<f32> {
+        let arr = transform.apply_image(img);
+
+        let rank = self
+            .encoder
+            .inputs
+            .iter()
+            .find(|input| input.name == self.encoder_input)
+            .and_then(|input| input.input_type.tensor_dimensions())
+            .map_or(3, |dims| dims.len());
+        let arr = if rank == 4 {
+            arr.permuted_axes([2, 0, 1])
+                .insert_axis(Axis(0))
+                .as_standard_layout()
+                .into_owned()
+                .into_dyn()
+        } else {
+            arr.into_dyn()
+        };
+
+        arr
+    }
+
+    fn preprocess_prompts(&self, prompts: Vec<Prompt>) -> Result<(Array3<f32>, Array2<f32>)> {
+        let mut points = Vec::new();
+        let mut labels = Vec::new();
+        for prompt in prompts.into_iter() {
+            let (one_points, one_labels): (Vec<f32>, Vec<f32>) = prompt.into();
+            let one_points: Vec<f32> = one_points
+                .chunks(2) // make points to Vec<(f32, f32)>
+                .flat_map(|chunk| {
+                    // each chunk is (f32, f32)
+                    self.transform.apply_coords([chunk[0], chunk[1]]) // returns [f32; 2]
+                })
+                .collect(); // collect to Vec<f32>
+
+            points.extend(one_points);
+            labels.extend(one_labels);
+        }
+
+        let points = Array3::from_shape_vec((1, labels.len(), 2), points)?;
+        let labels = Array2::from_shape_vec((1, labels.len()), labels.clone())?;
+
+        Ok((points, labels))
+    }
+
+    // the masks are (1, n, h, w), either in the original size or in the padded input space
+    // the ious are (1, n), the candidates are in the same order as the masks
+    fn postprocess(
+        masks: ArrayViewD<f32>,
+        ious: Option<ArrayViewD<f32>>,
+        transform: &ResizeLongestSide,
+    ) -> Result<Vec<MaskCandidate>> {
+        let shape = masks.shape();
+        if shape.len() < 2 {
+            return Err(Error::Inference(format!(
+                "unexpected mask shape from decoder: {shape:?}"
+            )));
+        }
+        let (h, w) = (shape[shape.len() - 2], shape[shape.len() - 1]);
+        let logits: Vec<f32> = masks.iter().copied().collect();
+        if logits.is_empty() || !logits.len().is_multiple_of(h * w) {
+            return Err(Error::Inference(format!(
+                "unexpected mask shape from decoder: {shape:?}"
+            )));
+        }
+
+        let ious: Vec<f32> = ious.map_or(Vec::new(), |ious| ious.iter().copied().collect());
+
+        let mut candidates = Vec::new();
+        for (i, logits) in logits.chunks(h * w).enumerate() {
+            let mask: Vec<u8> = logits
+                .iter()
+                .map(|&v| if v > MASK_THRESHOLD { 255u8 } else { 0u8 })
+                .collect();
+            let mask = image::GrayImage::from_raw(w as u32, h as u32, mask)
+                .ok_or_else(|| Error::Inference("unexpected mask size from decoder".into()))?;
+
+            candidates.push(MaskCandidate {
+                mask: DynamicImage::ImageLuma8(transform.restore_mask(&mask)),
+                iou: ious.get(i).copied().unwrap_or(1.0),
+                stability: Self::stability_score(logits),
+            });
+        }
+
+        Ok(candidates)
+    }
+
+    // the iou between the masks from a higher and a lower threshold
+    fn stability_score(logits: &[f32]) -> f32 {
+        let high = logits
+            .iter()
+            .filter(|&&v| v > MASK_THRESHOLD + STABILITY_OFFSET)
+            .count();
+        let low = logits
+            .iter()
+            .filter(|&&v| v > MASK_THRESHOLD - STABILITY_OFFSET)
+            .count();
+
+        if low == 0 {
+            0.0
+        } else {
+            high as f32 / low as f32
+        }
+    }
+}
+
+impl Segmenter for SAMmodel {
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)> {
+        let (encoder, decoder) = self.family.names();
+        vec![
+            (encoder, self.encoder_provider.clone()),
+            (decoder, self.decoder_provider.clone()),
+        ]
+    }
+
+    fn signatures(&self) -> Vec<Signature> {
+        self.signatures.clone()
+    }
+
+    fn encode(&self, img: &DynamicImage) -> Result<Embedding> {
+        SAMmodel::encode(self, img)
+    }
+
+    fn set_embedding(&mut self, embedding: Embedding) {
+        SAMmodel::set_embedding(self, embedding)
+    }
+
+    fn generate_masks(&mut self, batch: Vec<Vec<Prompt>>) -> Result<Vec<Vec<MaskCandidate>>> {
+        SAMmodel::generate_masks(self, batch)
+    }
+
+    fn select_candidate(&mut self, prompts: &[Prompt], index: usize) {
+        SAMmodel::select_candidate(self, prompts, index)
+    }
+
+    fn segment_everything(
+        &mut self,
+        img: &DynamicImage,
+        config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<auto::AutoMask>>> {
+        SAMmodel::segment_everything(self, img, config, cancelled)
+    }
+
+    fn batch_size(&self) -> usize {
+        SAMmodel::batch_size(self)
+    }
+}
+
+impl MaskHistory {
+    // the entry of the longest previous prompts that the given prompts start with
+    fn entry(&self, prompts: &[Prompt]) -> Option<usize> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.prompts.len() < prompts.len() && prompts.starts_with(&e.prompts))
+            .max_by_key(|(_, e)| e.prompts.len())
+            .map(|(i, _)| i)
+    }
+
+    // the logits of the selected mask to feed back for the given prompts
+    fn previous(&self, prompts: &[Prompt]) -> Option<&Array4<f32>> {
+        let entry = &self.0[self.entry(prompts)?];
+        entry.logits.get(entry.selected)
+    }
+
+    // the candidate the user has for the masks the given prompts refine
+    fn select(&mut self, prompts: &[Prompt], selected: usize) {
+        if let Some(i) = self.entry(prompts) {
+            let entry = &mut self.0[i];
+            if selected < entry.logits.len() {
+                entry.selected = selected;
+            }
+        }
+    }
+
+    fn keep(&mut self, prompts: Vec<Prompt>, logits: Vec<Array4<f32>>) {
+        self.0.retain(|e| e.prompts != prompts);
+        if self.0.len() >= LOGITS_LIMIT {
+            self.0.remove(0);
+        }
+        self.0.push(PreviousMasks {
+            prompts,
+            logits,
+            selected: 0,
+        });
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl DecoderPrompts {
+    // the prompts of every instance are padded to the same length with points labeled -1,
+    // which the decoder ignores. previous is the mask fed back for each instance
+    fn new(prompts: &[(Array3<f32>, Array2<f32>)], previous: &[Option<&Array4<f32>>]) -> Self {
+        let b = prompts.len();
+        let n = prompts.iter().map(|(_, l)| l.len()).max().unwrap_or(0);
+
+        let mut points = Array3::<f32>::zeros((b, n, 2));
+        let mut labels = Array2::<f32>::from_elem((b, n), -1.0);
+        for (i, (one_points, one_labels)) in prompts.iter().enumerate() {
+            let len = one_labels.len();
+            points
+                .slice_mut(s![i, ..len, ..])
+                .assign(&one_points.index_axis(Axis(0), 0));
+            labels.slice_mut(s![i, ..len]).assign(&one_labels.row(0));
+        }
+
+        let mut mask_input = Array4::<f32>::zeros((b, 1, 256, 256));
+        let mut has_mask_input = Array1::<f32>::zeros(b);
+        for (i, logits) in previous.iter().enumerate() {
+            if let Some(logits) = logits {
+                mask_input
+                    .slice_mut(s![i..i + 1, .., .., ..])
+                    .assign(*logits);
+                has_mask_input[i] = 1.0;
+            }
+        }
+
+        Self {
+            points,
+            labels,
+            mask_input,
+            has_mask_input,
+        }
+    }
+}
+
+impl Family {
+    // found from the names of the encoder inputs and outputs
+    fn of(encoder: &Session) -> Self {
+        let inputs = encoder.inputs.iter().map(|input| input.name.as_str());
+        let outputs = encoder.outputs.iter().map(|output| output.name.as_str());
+        Self::from_names(inputs.chain(outputs))
+    }
+
+    // SAM2 and SAM-HQ encoders give extra features, EfficientSAM encoders take another image
+    fn from_names<'a>(names: impl Iterator<Item = &'a str>) -> Self {
+        let names: Vec<_> = names.collect();
+        let has = |spec: &Spec| names.iter().any(|name| spec.names.contains(name));
+        if names.contains(&EFFICIENT_SAM_IMAGE) {
+            Family::EfficientSam
+        } else if has(&SAM2_EMBEDDING[1]) {
+            Family::Sam2
+        } else if has(&SAM_HQ_EMBEDDING[1]) {
+            Family::SamHq
+        } else {
+            Family::Sam
+        }
+    }
+
+    fn embedding(self) -> &'static [Spec] {
+        match self {
+            Family::Sam | Family::EfficientSam => &SAM_EMBEDDING,
+            Family::Sam2 => &SAM2_EMBEDDING,
+            Family::SamHq => &SAM_HQ_EMBEDDING,
+        }
+    }
+
+    // the embedding with a dynamic batch, then the prompts and the size
+    fn decoder_inputs(self) -> Vec<Spec> {
+        let mut inputs = self.embedding().to_vec();
+        match self {
+            Family::Sam | Family::SamHq => {
+                inputs.extend(PROMPT_INPUTS);
+                inputs.push(SAM_ORIG_SIZE);
+            }
+            Family::Sam2 => {
+                inputs.extend(PROMPT_INPUTS);
+                inputs.push(ANY_ORIG_SIZE);
+            }
+            Family::EfficientSam => {
+                inputs.extend(EFFICIENT_SAM_PROMPT_INPUTS);
+                inputs.push(ANY_ORIG_SIZE);
+            }
+        }
+
+        inputs
+    }
+
+    fn decoder_outputs(self) -> &'static [Spec] {
+        match self {
+            Family::EfficientSam => &EFFICIENT_SAM_OUTPUTS,
+            _ => &DECODER_OUTPUTS,
+        }
+    }
+
+    // of the encoder and the decoder
+    fn names(self) -> (&'static str, &'static str) {
+        match self {
+            Family::Sam => ("SAM Encoder", "SAM Decoder"),
+            Family::Sam2 => ("SAM2 Encoder", "SAM2 Decoder"),
+            Family::SamHq => ("SAM-HQ Encoder", "SAM-HQ Decoder"),
+            Family::EfficientSam => ("EfficientSAM Encoder", "EfficientSAM Decoder"),
+        }
+    }
+
+    // SAM pads the image to a square, SAM2 stretches it,
+    // EfficientSAM takes the points in pixels and gives the masks in the original size
+    fn transform(self, ori_w: u32, ori_h: u32) -> ResizeLongestSide {
+        match self {
+            Family::Sam | Family::SamHq => ResizeLongestSide::new(ori_w, ori_h),
+            Family::Sam2 => ResizeLongestSide::stretch(ori_w, ori_h),
+            Family::EfficientSam => ResizeLongestSide::original(ori_w, ori_h),
+        }
+    }
+}
+
+impl Embedding {
+    // an embedding without data, for segmenters that do not encode
+    pub(super) fn empty(ori_w: u32, ori_h: u32) -> Self {
+        Self {
+            data: Vec::new(),
+            transform: ResizeLongestSide::new(ori_w, ori_h),
+        }
+    }
+
+    // the size of the original image
+    pub(super) fn size(&self) -> (u32, u32) {
+        (self.transform.ori_w, self.transform.ori_h)
+    }
+}
+
+impl MaskCandidate {
+    /// The IoU times the stability, used to pick the default candidate.
+    pub fn score(&self) -> f32 {
+        self.iou * self.stability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn decoder_prompts_are_padded_and_fed_back() {
+        // one point, then a box (two corners) and a negative point
+        let prompts = [
+            (array![[[1.0, 2.0]]], array![[1.0]]),
+            (
+                array![[[3.0, 4.0], [5.0, 6.0], [7.0, 8.0]]],
+                array![[2.0, 3.0, 0.0]],
+            ),
+        ];
+        let previous = Array4::from_elem((1, 1, 256, 256), 5.0);
+
+        let inputs = DecoderPrompts::new(&prompts, &[None, Some(&previous)]);
+        assert_eq!(inputs.points.dim(), (2, 3, 2));
+        assert_eq!(inputs.labels, array![[1.0, -1.0, -1.0], [2.0, 3.0, 0.0]]);
+        assert_eq!(
+            inputs.points.slice(s![0, .., ..]),
+            array![[1.0, 2.0], [0.0, 0.0], [0.0, 0.0]]
+        );
+        assert_eq!(inputs.points.slice(s![1, 2, ..]), array![7.0, 8.0]);
+
+        assert_eq!(inputs.has_mask_input, array![0.0, 1.0]);
+        assert_eq!(inputs.mask_input.dim(), (2, 1, 256, 256));
+        assert!(inputs
+            .mask_input
+            .index_axis(Axis(0), 0)
+            .iter()
+            .all(|&v| v == 0.0));
+        assert!(inputs
+            .mask_input
+            .index_axis(Axis(0), 1)
+            .iter()
+            .all(|&v| v == 5.0));
+    }
+
+    #[test]
+    fn selected_logits_are_fed_back() {
+        let point = |x| Prompt::new_point(x, 0.5, 1.0);
+        let logits = |v| Array4::from_elem((1, 1, 256, 256), v);
+        let mut history = MaskHistory::default();
+        history.keep(
+            vec![point(0.1)],
+            vec![logits(1.0), logits(2.0), logits(3.0)],
+        );
+
+        let refined = [point(0.1), point(0.2)];
+        assert_eq!(history.previous(&refined).unwrap()[[0, 0, 0, 0]], 1.0);
+        history.select(&refined, 2);
+        assert_eq!(history.previous(&refined).unwrap()[[0, 0, 0, 0]], 3.0);
+        // out of range keeps the selection
+        history.select(&refined, 3);
+        assert_eq!(history.previous(&refined).unwrap()[[0, 0, 0, 0]], 3.0);
+
+        // decoding the same prompts again resets it to the best
+        history.keep(vec![point(0.1)], vec![logits(4.0), logits(5.0)]);
+        assert_eq!(history.previous(&refined).unwrap()[[0, 0, 0, 0]], 4.0);
+        assert!(history.previous(&[point(0.1)]).is_none());
+    }
+
+    #[test]
+    fn sam2_stretches_the_image() {
+        let transform = Family::Sam2.transform(400, 100);
+        assert_eq!((transform.new_w, transform.new_h), (1024, 1024));
+        let transform = Family::Sam.transform(400, 100);
+        assert_eq!((transform.new_w, transform.new_h), (1024, 256));
+
+        let sam2 = [
+            "image",
+            "high_res_feats_0",
+            "high_res_feats_1",
+            "image_embed",
+        ];
+        assert_eq!(Family::from_names(sam2.into_iter()), Family::Sam2);
+        let sam = ["input_image", "image_embeddings"];
+        assert_eq!(Family::from_names(sam.into_iter()), Family::Sam);
+
+        assert_eq!(Family::Sam2.embedding().len(), 3);
+        assert_eq!(Family::Sam2.decoder_inputs()[0].names, &["image_embed"]);
+        assert_eq!(Family::Sam.decoder_inputs()[0].names, &["image_embeddings"]);
+    }
+
+    #[test]
+    fn sam_hq_and_efficient_sam_are_found_by_name() {
+        let hq = ["input_image", "image_embeddings", "interm_embeddings"];
+        assert_eq!(Family::from_names(hq.into_iter()), Family::SamHq);
+        let efficient = ["batched_images", "image_embeddings"];
+        assert_eq!(
+            Family::from_names(efficient.into_iter()),
+            Family::EfficientSam
+        );
+
+        let names = |specs: Vec<Spec>| specs.iter().map(|s| s.names[0]).collect::<Vec<_>>();
+        assert!(names(Family::SamHq.decoder_inputs()).contains(&"interm_embeddings"));
+        let inputs = names(Family::EfficientSam.decoder_inputs());
+        assert!(inputs.contains(&"batched_point_coords"));
+        assert!(!inputs.contains(&"mask_input"));
+        assert_eq!(
+            Family::EfficientSam.decoder_outputs()[0].names,
+            &["output_masks"]
+        );
+
+        // the points stay in pixels
+        let transform = Family::EfficientSam.transform(400, 100);
+        assert_eq!((transform.new_w, transform.new_h), (400, 100));
+    }
+
+    #[test]
+    fn embedding_shapes_come_from_the_encoder() {
+        let dims = [1, 256, 64, 64];
+        let shape = SAMmodel::embedding_shape(&SAM_EMBEDDING[0], &dims);
+        assert_eq!(shape, Some((1, 256, 64, 64)));
+
+        // the layers and the width of the ViT, without the batch dimension
+        let dims = [4, 1, 64, 64, 768];
+        let shape = SAMmodel::embedding_shape(&SAM_HQ_EMBEDDING[1], &dims);
+        assert_eq!(shape, Some((4, 64, 64, 768)));
+        let dims = [-1, 1, 64, 64, 768];
+        assert_eq!(SAMmodel::embedding_shape(&SAM_HQ_EMBEDDING[1], &dims), None);
+
+        let data = Array4::zeros((4, 64, 64, 768));
+        let tensor = SAMmodel::decoder_tensor(&SAM_HQ_EMBEDDING[1], data.view());
+        assert_eq!(tensor.shape(), &[4, 1, 64, 64, 768]);
+    }
+
+    #[test]
+    fn item_keeps_the_batch_dimension() {
+        let batch = array![[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]].into_dyn();
+        let view = batch.view();
+
+        let item = SAMmodel::item(&view, 1);
+        assert_eq!(item.shape(), &[1, 3]);
+        assert_eq!(
+            item.iter().copied().collect::<Vec<_>>(),
+            vec![4.0, 5.0, 6.0]
+        );
+    }
+}
@@ -0,0 +1,368 @@
+//! Masks of everything in an image from a grid of point prompts.
+
+use super::prompt::Prompt;
+use super::{MaskCandidate, SAMmodel};
+use crate::config::AutoMaskConfig;
+use crate::error::Result;
+
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+// in pixels, a mask this close to the inner edge of a crop has been cut by it
+const CROP_EDGE_TOLERANCE: u32 = 20;
+
+/// A mask found without prompts from the user, with the grid point that gives it.
+pub struct AutoMask {
+    /// Normalized in the whole image.
+    pub point: [f32; 2],
+    /// The mask has the size of the whole image.
+    pub candidate: MaskCandidate,
+}
+
+// a kept mask with what mask nms needs
+struct Region {
+    point: [f32; 2],
+    candidate: MaskCandidate,
+    mask: GrayImage,
+    bbox: [u32; 4], // x1, y1, x2, y2, exclusive
+    area: u64,
+}
+
+impl SAMmodel {
+    /// Masks of everything in the image, from a grid of points on the image and on its crops.
+    ///
+    /// The current image is kept, returns None if cancelled.
+    pub fn segment_everything(
+        &mut self,
+        img: &DynamicImage,
+        config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<AutoMask>>> {
+        // the grid would push the refinement logits of the current image out,
+        // and the crops replace its embedding
+        let history = std::mem::take(&mut self.history);
+        let embedding = self.embedding.clone();
+        let transform = self.transform;
+
+        let result = self.segment_crops(img, config, cancelled);
+
+        self.embedding = embedding;
+        self.transform = transform;
+        self.history = history;
+
+        result
+    }
+
+    fn segment_crops(
+        &mut self,
+        img: &DynamicImage,
+        config: &AutoMaskConfig,
+        cancelled: &mut dyn FnMut() -> bool,
+    ) -> Result<Option<Vec<AutoMask>>> {
+        let (w, h) = img.dimensions();
+
+        let mut kept: Vec<Region> = Vec::new();
+        for (layer, crop) in crop_boxes(w, h, config.crop_layers, config.crop_overlap) {
+            let grid = layer_grid(config.points_per_side, layer);
+            // the first crop is the whole image, which has been embedded
+            if layer > 0 {
+                let sub = img.crop_imm(crop[0], crop[1], crop[2], crop[3]);
+                let embedding = self.encode(&sub)?;
+                self.set_embedding(embedding);
+            }
+
+            for chunk in grid.chunks(self.batch_size()) {
+                if cancelled() {
+                    return Ok(None);
+                }
+
+                let batch = chunk
+                    .iter()
+                    .map(|p| vec![Prompt::new_point(p[0], p[1], 1.0)])
+                    .collect();
+                for (p, candidates) in chunk.iter().zip(self.generate_masks(batch)?) {
+                    let point = [
+                        (crop[0] as f32 + p[0] * crop[2] as f32) / w as f32,
+                        (crop[1] as f32 + p[1] * crop[3] as f32) / h as f32,
+                    ];
+                    for c in candidates {
+                        if c.iou < config.iou_threshold || c.stability < config.stability_threshold
+                        {
+                            continue;
+                        }
+                        let Some(region) = Region::new(point, c, crop, w, h) else {
+                            continue;
+                        };
+                        // the whole object is found on the image or on a larger crop
+                        if !region.touches_crop_edge(crop, w, h) {
+                            insert_nms(&mut kept, region, config.nms_threshold);
+                        }
+                    }
+                }
+            }
+        }
+
+        kept.sort_by(|a, b| b.candidate.score().total_cmp(&a.candidate.score()));
+        let masks = kept
+            .into_iter()
+            .map(|r| AutoMask {
+                point: r.point,
+                candidate: MaskCandidate {
+                    mask: DynamicImage::ImageLuma8(r.mask),
+                    ..r.candidate
+                },
+            })
+            .collect();
+
+        Ok(Some(masks))
+    }
+}
+
+impl Region {
+    // the mask of the candidate has the size of the crop, put it back into the whole image
+    // None if the mask is empty
+    fn new(
+        point: [f32; 2],
+        candidate: MaskCandidate,
+        crop: [u32; 4],
+        w: u32,
+        h: u32,
+    ) -> Option<Self> {
+        let crop_mask = candidate.mask.to_luma8();
+        let mask = if crop == [0, 0, w, h] {
+            crop_mask
+        } else {
+            let mut mask = GrayImage::new(w, h);
+            image::imageops::replace(&mut mask, &crop_mask, crop[0] as i64, crop[1] as i64);
+            mask
+        };
+
+        let (mut x1, mut y1, mut x2, mut y2) = (w, h, 0, 0);
+        let mut area = 0;
+        for (x, y, p) in mask.enumerate_pixels() {
+            if p.0[0] > 0 {
+                x1 = x1.min(x);
+                y1 = y1.min(y);
+                x2 = x2.max(x + 1);
+                y2 = y2.max(y + 1);
+                area += 1;
+            }
+        }
+        if area == 0 {
+            return None;
+        }
+
+        Some(Region {
+            point,
+            candidate,
+            mask,
+            bbox: [x1, y1, x2, y2],
+            area,
+        })
+    }
+
+    // cut by an edge of the crop that is not an edge of the image
+    fn touches_crop_edge(&self, crop: [u32; 4], w: u32, h: u32) -> bool {
+        let crop_edges = [crop[0], crop[1], crop[0] + crop[2], crop[1] + crop[3]];
+        let image_edges = [0, 0, w, h];
+        self.bbox
+            .iter()
+            .zip(crop_edges)
+            .zip(image_edges)
+            .any(|((&b, c), i)| {
+                b.abs_diff(c) <= CROP_EDGE_TOLERANCE && b.abs_diff(i) > CROP_EDGE_TOLERANCE
+            })
+    }
+
+    fn iou(&self, other: &Region) -> f32 {
+        let x1 = self.bbox[0].max(other.bbox[0]);
+        let y1 = self.bbox[1].max(other.bbox[1]);
+        let x2 = self.bbox[2].min(other.bbox[2]);
+        let y2 = self.bbox[3].min(other.bbox[3]);
+        if x1 >= x2 || y1 >= y2 {
+            return 0.0;
+        }
+
+        let mut intersection = 0u64;
+        for y in y1..y2 {
+            for x in x1..x2 {
+                if self.mask.get_pixel(x, y).0[0] > 0 && other.mask.get_pixel(x, y).0[0] > 0 {
+                    intersection += 1;
+                }
+            }
+        }
+
+        intersection as f32 / (self.area + other.area - intersection) as f32
+    }
+}
+
+// greedy mask nms as the masks come: a region is dropped if a better one overlaps it,
+// otherwise it replaces the worse ones it overlaps
+fn insert_nms(kept: &mut Vec<Region>, region: Region, threshold: f32) {
+    let score = region.candidate.score();
+    let overlapped: Vec<usize> = (0..kept.len())
+        .filter(|&i| kept[i].iou(&region) > threshold)
+        .collect();
+
+    if overlapped
+        .iter()
+        .any(|&i| kept[i].candidate.score() >= score)
+    {
+        return;
+    }
+
+    for i in overlapped.into_iter().rev() {
+        kept.remove(i);
+    }
+    kept.push(region);
+}
+
+// n x n points at the centers of the cells, normalized
+fn point_grid(n: usize) -> Vec<[f32; 2]> {
+    let n = n.max(1);
+    let step = 1.0 / n as f32;
+    let mut points = Vec::with_capacity(n * n);
+    for y in 0..n {
+        for x in 0..n {
+            points.push([(x as f32 + 0.5) * step, (y as f32 + 0.5) * step]);
+        }
+    }
+
+    points
+}
+
+// the crops are smaller, so are their grids, points_per_side / 2^layer, the same as SAM
+fn layer_grid(points_per_side: usize, layer: usize) -> Vec<[f32; 2]> {
+    point_grid(points_per_side >> layer)
+}
+
+// the layer and the x, y, w, h of the crops, the whole image first as layer 0,
+// then 2^layer x 2^layer overlapping crops for each layer, the same as SAM
+fn crop_boxes(w: u32, h: u32, layers: usize, overlap_ratio: f32) -> Vec<(usize, [u32; 4])> {
+    let mut boxes = vec![(0, [0, 0, w, h])];
+
+    for layer in 1..=layers {
+        let n = 1u32 << layer;
+        let overlap = (overlap_ratio * w.min(h) as f32 * 2.0 / n as f32) as u32;
+        let crop_w = (w + overlap * (n - 1)).div_ceil(n).min(w);
+        let crop_h = (h + overlap * (n - 1)).div_ceil(n).min(h);
+
+        for j in 0..n {
+            for i in 0..n {
+                let x = (crop_w.saturating_sub(overlap) * i).min(w - crop_w);
+                let y = (crop_h.saturating_sub(overlap) * j).min(h - crop_h);
+                boxes.push((layer, [x, y, crop_w, crop_h]));
+            }
+        }
+    }
+
+    boxes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x1: u32, y1: u32, x2: u32, y2: u32, iou: f32) -> Region {
+        let mut mask = GrayImage::new(10, 10);
+        for y in y1..y2 {
+            for x in x1..x2 {
+                mask.put_pixel(x, y, image::Luma([255]));
+            }
+        }
+        let candidate = MaskCandidate {
+            mask: DynamicImage::ImageLuma8(mask),
+            iou,
+            stability: 1.0,
+        };
+
+        Region::new([0.5, 0.5], candidate, [0, 0, 10, 10], 10, 10).unwrap()
+    }
+
+    #[test]
+    fn grid_covers_cell_centers() {
+        let grid = point_grid(2);
+        assert_eq!(
+            grid,
+            vec![[0.25, 0.25], [0.75, 0.25], [0.25, 0.75], [0.75, 0.75]]
+        );
+        assert!(point_grid(32).iter().all(|p| p[0] < 1.0 && p[1] < 1.0));
+    }
+
+    #[test]
+    fn crops_stay_inside_the_image() {
+        let boxes = crop_boxes(640, 480, 2, 512.0 / 1500.0);
+        // the whole image, 2x2 and 4x4
+        assert_eq!(boxes.len(), 1 + 4 + 16);
+        assert_eq!(boxes[0], (0, [0, 0, 640, 480]));
+        for (_, [x, y, w, h]) in &boxes {
+            assert!(x + w <= 640 && y + h <= 480);
+        }
+
+        // 32 x 32 points on the image, 16 x 16 and 8 x 8 on the crops
+        let layers: Vec<usize> = boxes.iter().map(|(layer, _)| *layer).collect();
+        assert_eq!(&layers[..6], &[0, 1, 1, 1, 1, 2]);
+        let sizes: Vec<usize> = [0, 1, 2]
+            .iter()
+            .map(|&layer| layer_grid(32, layer).len())
+            .collect();
+        assert_eq!(sizes, vec![32 * 32, 16 * 16, 8 * 8]);
+    }
+
+    #[test]
+    fn masks_cut_by_a_crop_are_dropped() {
+        let crop = [0, 0, 60, 60];
+        let region = |x1, y1, x2, y2| {
+            let mut mask = GrayImage::new(60, 60);
+            for y in y1..y2 {
+                for x in x1..x2 {
+                    mask.put_pixel(x, y, image::Luma([255]));
+                }
+            }
+            let candidate = MaskCandidate {
+                mask: DynamicImage::ImageLuma8(mask),
+                iou: 1.0,
+                stability: 1.0,
+            };
+            Region::new([0.0, 0.0], candidate, crop, 200, 200).unwrap()
+        };
+
+        // inside the crop, and at the top left where the crop and the image share an edge
+        assert!(!region(25, 25, 35, 35).touches_crop_edge(crop, 200, 200));
+        assert!(!region(0, 0, 10, 10).touches_crop_edge(crop, 200, 200));
+        // cut by the right edge of the crop, which is inside the image
+        assert!(region(30, 25, 60, 35).touches_crop_edge(crop, 200, 200));
+        // the whole image has no inner edge
+        let whole = region(30, 25, 60, 35);
+        assert!(!whole.touches_crop_edge([0, 0, 60, 60], 60, 60));
+    }
+
+    #[test]
+    fn region_in_crop_is_placed_back() {
+        let mut mask = GrayImage::new(4, 4);
+        mask.put_pixel(1, 2, image::Luma([255]));
+        let candidate = MaskCandidate {
+            mask: DynamicImage::ImageLuma8(mask),
+            iou: 1.0,
+            stability: 1.0,
+        };
+
+        let region = Region::new([0.0, 0.0], candidate, [5, 3, 4, 4], 10, 10).unwrap();
+        assert_eq!(region.bbox, [6, 5, 7, 6]);
+        assert_eq!(region.area, 1);
+    }
+
+    #[test]
+    fn mask_nms_keeps_the_better_of_duplicates() {
+        let mut kept = Vec::new();
+        insert_nms(&mut kept, region(0, 0, 5, 5, 0.8), 0.7);
+        // almost the same mask with a higher score replaces it
+        insert_nms(&mut kept, region(0, 0, 5, 4, 0.9), 0.7);
+        // a worse duplicate is dropped
+        insert_nms(&mut kept, region(0, 0, 5, 5, 0.7), 0.7);
+        // a different one is kept
+        insert_nms(&mut kept, region(6, 6, 9, 9, 0.5), 0.7);
+
+        let scores: Vec<f32> = kept.iter().map(|r| r.candidate.iou).collect();
+        assert_eq!(scores, vec![0.9, 0.5]);
+    }
+}
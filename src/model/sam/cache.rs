@@ -0,0 +1,248 @@
+//! The on-disk cache of image embeddings.
+
+use super::Shape;
+use crate::config::CacheConfig;
+
+use image::DynamicImage;
+use ndarray::Array4;
+
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const EXTENSION: &str = "emb";
+
+/// SAM embeddings on disk, one file per image and encoder.
+///
+/// The modified time of a file is its last use, the oldest ones are removed above the size limit.
+#[derive(Debug)]
+pub struct EmbeddingCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    model_hash: blake3::Hash, // embeddings of another encoder are never used
+}
+
+impl EmbeddingCache {
+    /// The cache of an encoder, None if the cache is disabled or can not be used.
+    pub fn new(config: &CacheConfig, encoder_path: &str) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let init = || -> std::io::Result<Self> {
+            fs::create_dir_all(&config.dir)?;
+            let mut hasher = blake3::Hasher::new();
+            hasher.update_reader(File::open(encoder_path)?)?;
+
+            Ok(Self {
+                dir: PathBuf::from(&config.dir),
+                max_bytes: config.max_size_mb * 1024 * 1024,
+                model_hash: hasher.finalize(),
+            })
+        };
+
+        match init() {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                println!("Embedding cache disabled: {e}");
+                None
+            }
+        }
+    }
+
+    /// The key of an image, the hash of its pixels and of the encoder.
+    pub fn key(&self, img: &DynamicImage) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.model_hash.as_bytes());
+        hasher.update(&img.width().to_le_bytes());
+        hasher.update(&img.height().to_le_bytes());
+        hasher.update(img.as_bytes());
+        hasher.finalize().to_hex().to_string()
+    }
+
+    /// The embedding of a key, the tensors are stored one after another,
+    /// a missing or broken entry is a miss.
+    pub fn load(&self, key: &str, shapes: &[Shape]) -> Option<Vec<Array4<f32>>> {
+        let path = self.path(key);
+        let mut bytes = Vec::new();
+        File::open(&path).ok()?.read_to_end(&mut bytes).ok()?;
+
+        let total: usize = shapes.iter().map(|(n, c, h, w)| n * c * h * w).sum();
+        if bytes.len() != total * 4 {
+            return None;
+        }
+        let mut values = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let embedding = shapes
+            .iter()
+            .map(|&shape| {
+                let (n, c, h, w) = shape;
+                let data = values.by_ref().take(n * c * h * w).collect();
+                Array4::from_shape_vec(shape, data).ok()
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // mark it as recently used
+        if let Ok(f) = File::options().write(true).open(&path) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+
+        Some(embedding)
+    }
+
+    /// Write the embedding of a key, failing to store only costs the next encoding.
+    pub fn store(&self, key: &str, embedding: &[Array4<f32>]) {
+        let write = || -> std::io::Result<()> {
+            // write to a temporary file first, so a crash never leaves a broken entry
+            let tmp = self.dir.join(format!("{key}.tmp"));
+            let mut f = File::create(&tmp)?;
+            let bytes: Vec<u8> = embedding
+                .iter()
+                .flatten()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            f.write_all(&bytes)?;
+            fs::rename(&tmp, self.path(key))
+        };
+
+        if let Err(e) = write() {
+            println!("Failed to cache the embedding: {e}");
+            return;
+        }
+
+        if let Err(e) = self.evict() {
+            println!("Failed to evict the embedding cache: {e}");
+        }
+    }
+}
+
+// private
+impl EmbeddingCache {
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{EXTENSION}"))
+    }
+
+    // remove the least recently used entries until the cache fits in the limit
+    fn evict(&self) -> std::io::Result<()> {
+        let mut entries = Self::entries(&self.dir)?;
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+
+        entries.sort_by_key(|(_, _, used)| *used);
+        for (path, size, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total -= size;
+        }
+
+        Ok(())
+    }
+
+    // path, size and last use of every entry
+    fn entries(dir: &Path) -> std::io::Result<Vec<(PathBuf, u64, SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION) {
+                let meta = fs::metadata(&path)?;
+                entries.push((path, meta.len(), meta.modified()?));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cache(name: &str, max_bytes: u64) -> EmbeddingCache {
+        let dir = std::env::temp_dir().join(format!("label-sam-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        EmbeddingCache {
+            dir,
+            max_bytes,
+            model_hash: blake3::hash(b"encoder"),
+        }
+    }
+
+    // the last use of an entry, set explicitly as some filesystems only keep seconds
+    fn set_last_use(cache: &EmbeddingCache, key: &str, secs_ago: u64) {
+        let time = SystemTime::now() - std::time::Duration::from_secs(secs_ago);
+        fs::File::options()
+            .write(true)
+            .open(cache.path(key))
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn store_and_load() {
+        let cache = test_cache("roundtrip", u64::MAX);
+        let embedding = vec![
+            Array4::from_shape_fn((1, 2, 3, 4), |(_, c, h, w)| (c * 12 + h * 4 + w) as f32),
+            Array4::from_elem((1, 1, 2, 2), 7.0),
+        ];
+        let shapes = [(1, 2, 3, 4), (1, 1, 2, 2)];
+
+        cache.store("a", &embedding);
+        assert_eq!(cache.load("a", &shapes), Some(embedding));
+        // the size does not match
+        assert_eq!(cache.load("a", &[(1, 2, 3, 4)]), None);
+        assert_eq!(cache.load("a", &[(1, 2, 3, 5), (1, 1, 2, 2)]), None);
+        assert_eq!(cache.load("b", &shapes), None);
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        // room for two embeddings of 96 bytes
+        let cache = test_cache("evict", 200);
+        let embedding = vec![Array4::<f32>::zeros((1, 2, 3, 4))];
+
+        cache.store("a", &embedding);
+        cache.store("b", &embedding);
+        set_last_use(&cache, "a", 300);
+        set_last_use(&cache, "b", 200);
+        // using a makes b the oldest
+        cache.load("a", &[(1, 2, 3, 4)]).unwrap();
+        cache.store("c", &embedding);
+
+        assert!(cache.load("a", &[(1, 2, 3, 4)]).is_some());
+        assert!(cache.load("b", &[(1, 2, 3, 4)]).is_none());
+        assert!(cache.load("c", &[(1, 2, 3, 4)]).is_some());
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn key_depends_on_pixels_and_model() {
+        let cache = test_cache("key", u64::MAX);
+        let img = DynamicImage::new_rgb8(4, 4);
+        let mut other = img.to_rgb8();
+        other.put_pixel(0, 0, image::Rgb([1, 2, 3]));
+        let other = DynamicImage::ImageRgb8(other);
+
+        assert_eq!(cache.key(&img), cache.key(&img.clone()));
+        assert_ne!(cache.key(&img), cache.key(&other));
+
+        let another_model = EmbeddingCache {
+            model_hash: blake3::hash(b"another encoder"),
+            ..test_cache("key-model", u64::MAX)
+        };
+        assert_ne!(cache.key(&img), another_model.key(&img));
+
+        fs::remove_dir_all(&cache.dir).unwrap();
+        fs::remove_dir_all(&another_model.dir).unwrap();
+    }
+}
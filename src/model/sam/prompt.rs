@@ -1,10 +1,17 @@
-#[derive(Debug, Clone, Copy)]
+//! The prompts of SAM.
+
+/// A prompt of SAM in coordinates normalized to the image,
+/// a point with its label (1 for the object, 0 for the background) or a box.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Prompt {
-    Point(([f32; 2], f32)), // point, label
-    Box([f32; 4]),          // box, left-top x, left-top y, right-bottom x, right-bottom y
+    /// A point and its label.
+    Point(([f32; 2], f32)),
+    /// The left-top x and y, then the right-bottom x and y.
+    Box([f32; 4]),
 }
 
 impl Prompt {
+    /// A point in `[0, 1)`, the label is 1 for the object or 0 for the background.
     pub fn new_point(x: f32, y: f32, label: f32) -> Self {
         assert!(label == 0.0 || label == 1.0);
         assert!((0f32..1f32).contains(&x));
@@ -13,6 +20,7 @@ impl Prompt {
         Self::Point(([x, y], label))
     }
 
+    /// A box in `[0, 1]`, the first corner is the left-top one.
     pub fn new_box(x1: f32, y1: f32, x2: f32, y2: f32) -> Self {
         assert!(x1 < x2 && y1 < y2);
         assert!(x1 >= 0.0 && x2 <= 1.0);
@@ -22,8 +30,8 @@ impl Prompt {
     }
 }
 
-impl From<crate::app::model::yolo::BoundingBox> for Prompt {
-    fn from(bb: crate::app::model::yolo::BoundingBox) -> Self {
+impl From<crate::model::yolo::BoundingBox> for Prompt {
+    fn from(bb: crate::model::yolo::BoundingBox) -> Self {
         Prompt::new_box(bb.x1, bb.y1, bb.x2, bb.y2)
     }
 }
@@ -0,0 +1,147 @@
+//! The resizing and padding of images for the SAM encoders, and of masks back.
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView, GrayImage};
+use ndarray::Array3;
+
+/// The side length of the square image that SAM encoder takes.
+pub const TARGET_LENGTH: u32 = 1024;
+
+// in RGB order, the same as the original SAM
+const PIXEL_MEAN: [f32; 3] = [123.675, 116.28, 103.53];
+const PIXEL_STD: [f32; 3] = [58.395, 57.12, 57.375];
+
+/// Resize the longest side of an image to TARGET_LENGTH while keeping the aspect ratio,
+/// the rest of the TARGET_LENGTH x TARGET_LENGTH input is padded with zeros at right and bottom.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResizeLongestSide {
+    /// Width of the original image.
+    pub ori_w: u32,
+    /// Height of the original image.
+    pub ori_h: u32,
+    /// Width of the resized image in the input.
+    pub new_w: u32,
+    /// Height of the resized image in the input.
+    pub new_h: u32,
+}
+
+impl ResizeLongestSide {
+    /// Keep the aspect ratio, as SAM and MobileSAM do.
+    pub fn new(ori_w: u32, ori_h: u32) -> Self {
+        let scale = TARGET_LENGTH as f32 / ori_w.max(ori_h) as f32;
+        let new_w = ((ori_w as f32 * scale).round() as u32).clamp(1, TARGET_LENGTH);
+        let new_h = ((ori_h as f32 * scale).round() as u32).clamp(1, TARGET_LENGTH);
+
+        Self {
+            ori_w,
+            ori_h,
+            new_w,
+            new_h,
+        }
+    }
+
+    /// The whole input without keeping the aspect ratio, as SAM2 does.
+    pub fn stretch(ori_w: u32, ori_h: u32) -> Self {
+        Self {
+            ori_w,
+            ori_h,
+            new_w: TARGET_LENGTH,
+            new_h: TARGET_LENGTH,
+        }
+    }
+
+    /// The original size, for encoders that resize the image themselves
+    /// and decoders that take the points in pixels.
+    pub fn original(ori_w: u32, ori_h: u32) -> Self {
+        Self {
+            ori_w,
+            ori_h,
+            new_w: ori_w,
+            new_h: ori_h,
+        }
+    }
+
+    /// Resize and normalize an image to a HWC array of TARGET_LENGTH x TARGET_LENGTH.
+    pub fn apply_image(&self, img: &DynamicImage) -> Array3<f32> {
+        let img = img.resize_exact(self.new_w, self.new_h, FilterType::Triangle);
+
+        // padded area stays zero, which is the mean after normalization
+        let side = TARGET_LENGTH as usize;
+        let mut arr = Array3::zeros((side, side, 3));
+        for pixel in img.pixels() {
+            let x = pixel.0 as usize;
+            let y = pixel.1 as usize;
+            let [r, g, b, _] = pixel.2 .0;
+
+            for (c, v) in [r, g, b].into_iter().enumerate() {
+                arr[[y, x, c]] = (v as f32 - PIXEL_MEAN[c]) / PIXEL_STD[c];
+            }
+        }
+
+        arr
+    }
+
+    /// Map a normalized point of the original image to the coordinate of the encoder input.
+    pub fn apply_coords(&self, point: [f32; 2]) -> [f32; 2] {
+        [point[0] * self.new_w as f32, point[1] * self.new_h as f32]
+    }
+
+    /// Crop the valid area of a mask of the padded input, at any resolution,
+    /// and resize it back to the original size.
+    pub fn restore_mask(&self, mask: &GrayImage) -> GrayImage {
+        if mask.dimensions() == (self.ori_w, self.ori_h) {
+            return mask.clone();
+        }
+
+        let ratio_w = mask.width() as f32 / TARGET_LENGTH as f32;
+        let ratio_h = mask.height() as f32 / TARGET_LENGTH as f32;
+        let crop_w = ((self.new_w as f32 * ratio_w).round() as u32).clamp(1, mask.width());
+        let crop_h = ((self.new_h as f32 * ratio_h).round() as u32).clamp(1, mask.height());
+
+        let cropped = image::imageops::crop_imm(mask, 0, 0, crop_w, crop_h).to_image();
+        let mut resized =
+            image::imageops::resize(&cropped, self.ori_w, self.ori_h, FilterType::Triangle);
+
+        // the interpolation blurs the edge, make it binary again
+        for p in resized.pixels_mut() {
+            p.0[0] = if p.0[0] > 127 { 255 } else { 0 };
+        }
+
+        resized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stretch_scales_each_axis() {
+        let transform = ResizeLongestSide::stretch(400, 100);
+        assert_eq!(transform.apply_coords([0.5, 0.5]), [512.0, 512.0]);
+        assert_eq!(transform.apply_coords([0.25, 1.0]), [256.0, 1024.0]);
+
+        // the padded transform keeps the aspect ratio
+        let transform = ResizeLongestSide::new(400, 100);
+        assert_eq!(transform.apply_coords([0.5, 1.0]), [512.0, 256.0]);
+    }
+
+    #[test]
+    fn restore_stretched_mask() {
+        // a low-res SAM2 mask covers the whole frame, here its left half
+        let mask = GrayImage::from_fn(256, 256, |x, _| {
+            image::Luma([if x < 128 { 255 } else { 0 }])
+        });
+
+        let restored = ResizeLongestSide::stretch(400, 100).restore_mask(&mask);
+        assert_eq!(restored.dimensions(), (400, 100));
+        assert_eq!(restored.get_pixel(10, 50).0[0], 255);
+        assert_eq!(restored.get_pixel(190, 0).0[0], 255);
+        assert_eq!(restored.get_pixel(210, 99).0[0], 0);
+        assert_eq!(restored.get_pixel(390, 50).0[0], 0);
+
+        // the same mask from the padded transform only has the top quarter of the frame
+        let restored = ResizeLongestSide::new(400, 100).restore_mask(&mask);
+        assert_eq!(restored.dimensions(), (400, 100));
+        assert_eq!(restored.get_pixel(10, 99).0[0], 255);
+    }
+}
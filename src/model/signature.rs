@@ -0,0 +1,277 @@
+//! The expected inputs and outputs of the onnx models, checked when they are loaded.
+
+use crate::error::{Error, Result};
+
+use ort::{Session, TensorElementType, ValueType};
+
+/// What a loader expects of an input or output of a model.
+#[derive(Debug, Clone, Copy)]
+pub struct Spec {
+    /// The first one is used in messages, the others are aliases.
+    pub names: &'static [&'static str],
+    /// None for any element type.
+    pub ty: Option<TensorElementType>,
+    /// -1 for any size, None for any rank.
+    pub shape: Option<&'static [i64]>,
+    /// Whether the model has to have it.
+    pub required: bool,
+}
+
+/// The inputs, outputs and metadata of a loaded model, for the diagnostics view.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    /// Which model of the app it is, e.g. the SAM encoder.
+    pub model: &'static str,
+    /// The file it was loaded from.
+    pub path: String,
+    /// The name and type of each input.
+    pub inputs: Vec<(String, String)>,
+    /// The name and type of each output.
+    pub outputs: Vec<(String, String)>,
+    /// The custom metadata of the model.
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Spec {
+    /// A required f32 tensor.
+    pub const fn f32(name: &'static [&'static str], shape: &'static [i64]) -> Self {
+        Self {
+            names: name,
+            ty: Some(TensorElementType::Float32),
+            shape: Some(shape),
+            required: true,
+        }
+    }
+
+    /// The model may not have it.
+    pub const fn optional(mut self) -> Self {
+        self.required = false;
+        self
+    }
+
+    /// A tensor of any rank and size.
+    pub const fn any_rank(mut self) -> Self {
+        self.shape = None;
+        self
+    }
+
+    /// A tensor of any element type.
+    pub const fn any_type(mut self) -> Self {
+        self.ty = None;
+        self
+    }
+
+    // the problem with the given tensor, None if it matches
+    fn mismatch(&self, value: &ValueType) -> Option<String> {
+        let ValueType::Tensor { ty, dimensions } = value else {
+            return Some(format!("is {value}, expected a tensor"));
+        };
+
+        let ty_ok = self.ty.is_none_or(|expected| expected == *ty);
+        // a dynamic dimension of the model takes any size
+        let shape_ok = self.shape.is_none_or(|expected| {
+            expected.len() == dimensions.len()
+                && expected
+                    .iter()
+                    .zip(dimensions)
+                    .all(|(e, d)| *e == -1 || *d == -1 || e == d)
+        });
+        if ty_ok && shape_ok {
+            return None;
+        }
+
+        let ty = self.ty.map_or("any type".to_string(), |ty| ty.to_string());
+        let shape = self.shape.map_or("any shape".to_string(), |shape| {
+            let dims: Vec<_> = shape
+                .iter()
+                .map(|d| {
+                    if *d == -1 {
+                        "dyn".to_string()
+                    } else {
+                        d.to_string()
+                    }
+                })
+                .collect();
+            format!("({})", dims.join(", "))
+        });
+        Some(format!("is {value}, expected {ty} {shape}"))
+    }
+}
+
+/// Check the inputs and outputs of a session against the specs,
+/// every input of the model has to be known as it is fed by name.
+pub fn check(path: &str, session: &Session, inputs: &[Spec], outputs: &[Spec]) -> Result<()> {
+    let model_inputs: Vec<_> = session
+        .inputs
+        .iter()
+        .map(|input| (input.name.as_str(), &input.input_type))
+        .collect();
+    let model_outputs: Vec<_> = session
+        .outputs
+        .iter()
+        .map(|output| (output.name.as_str(), &output.output_type))
+        .collect();
+
+    let mut problems = check_tensors("input", &model_inputs, inputs);
+    problems.extend(check_tensors("output", &model_outputs, outputs));
+    for (name, _) in &model_inputs {
+        if !inputs.iter().any(|spec| spec.names.contains(name)) {
+            problems.push(format!("unknown input `{name}`"));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Signature(path.to_string(), problems.join("; ")))
+    }
+}
+
+/// The name the model uses for a spec with aliases, None if it is missing.
+pub fn find(session: &Session, spec: &Spec) -> Option<String> {
+    session
+        .inputs
+        .iter()
+        .map(|input| &input.name)
+        .chain(session.outputs.iter().map(|output| &output.name))
+        .find(|name| spec.names.contains(&name.as_str()))
+        .cloned()
+}
+
+fn check_tensors(kind: &str, tensors: &[(&str, &ValueType)], specs: &[Spec]) -> Vec<String> {
+    let mut problems = Vec::new();
+    for spec in specs {
+        match tensors.iter().find(|(name, _)| spec.names.contains(name)) {
+            Some((name, value)) => {
+                if let Some(problem) = spec.mismatch(value) {
+                    problems.push(format!("{kind} `{name}` {problem}"));
+                }
+            }
+            None if spec.required => {
+                let names: Vec<_> = tensors.iter().map(|(name, _)| *name).collect();
+                problems.push(format!(
+                    "missing {kind} `{}`, the model has {}",
+                    spec.names[0],
+                    names.join(", ")
+                ));
+            }
+            None => (),
+        }
+    }
+
+    problems
+}
+
+impl Signature {
+    /// Read the inputs, outputs and metadata of a session.
+    pub fn of(model: &'static str, path: &str, session: &Session) -> Self {
+        let inputs = session
+            .inputs
+            .iter()
+            .map(|input| (input.name.clone(), input.input_type.to_string()))
+            .collect();
+        let outputs = session
+            .outputs
+            .iter()
+            .map(|output| (output.name.clone(), output.output_type.to_string()))
+            .collect();
+
+        let mut metadata = Vec::new();
+        if let Ok(meta) = session.metadata() {
+            for (key, value) in [
+                ("producer", meta.producer()),
+                ("graph", meta.name()),
+                ("description", meta.description()),
+                ("version", meta.version().map(|v| v.to_string())),
+            ] {
+                if let Ok(value) = value.map(|v| v.trim().to_string()) {
+                    if !value.is_empty() {
+                        metadata.push((key.to_string(), value));
+                    }
+                }
+            }
+            for key in meta.custom_keys().unwrap_or_default() {
+                if let Ok(Some(value)) = meta.custom(&key) {
+                    metadata.push((key, value));
+                }
+            }
+        }
+
+        Self {
+            model,
+            path: path.to_string(),
+            inputs,
+            outputs,
+            metadata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensor(ty: TensorElementType, dimensions: &[i64]) -> ValueType {
+        ValueType::Tensor {
+            ty,
+            dimensions: dimensions.to_vec(),
+        }
+    }
+
+    #[test]
+    fn dynamic_dimensions_match() {
+        let spec = Spec::f32(&["images"], &[1, 3, 640, 640]);
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Float32, &[1, 3, 640, 640]))
+            .is_none());
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Float32, &[-1, 3, -1, -1]))
+            .is_none());
+
+        let spec = Spec::f32(&["point_coords"], &[-1, -1, 2]);
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Float32, &[1, 5, 2]))
+            .is_none());
+    }
+
+    #[test]
+    fn wrong_type_or_shape_is_reported() {
+        let spec = Spec::f32(&["images"], &[1, 3, 640, 640]);
+        let problem = spec
+            .mismatch(&tensor(TensorElementType::Float32, &[1, 3, 320, 320]))
+            .unwrap();
+        assert!(problem.contains("(1, 3, 640, 640)"), "{problem}");
+        // another rank
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Float32, &[3, 640, 640]))
+            .is_some());
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Uint8, &[1, 3, 640, 640]))
+            .is_some());
+
+        let spec = spec.any_rank().any_type();
+        assert!(spec
+            .mismatch(&tensor(TensorElementType::Uint8, &[640, 640, 3]))
+            .is_none());
+    }
+
+    #[test]
+    fn missing_tensors_are_reported() {
+        let images = tensor(TensorElementType::Float32, &[1, 3, 640, 640]);
+        let tensors = [("input", &images)];
+        let specs = [
+            Spec::f32(&["images"], &[1, 3, 640, 640]),
+            Spec::f32(&["extra"], &[1]).optional(),
+        ];
+
+        let problems = check_tensors("input", &tensors, &specs);
+        assert_eq!(
+            problems,
+            vec!["missing input `images`, the model has input"]
+        );
+
+        // an alias is found
+        let specs = [Spec::f32(&["images", "input"], &[1, 3, 640, 640])];
+        assert!(check_tensors("input", &tensors, &specs).is_empty());
+    }
+}
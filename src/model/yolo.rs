@@ -0,0 +1,955 @@
+//! Ultralytics YOLO detection and segmentation exports.
+
+use image::{imageops::FilterType, GenericImageView, GrayImage};
+use ndarray::{s, Array, Array2, ArrayD, ArrayView3, ArrayViewD, Axis, Dim, Ix3};
+use ort::{inputs, Session, SessionOutputs};
+
+use super::signature::{self, Signature, Spec};
+use super::{provider, Detector, SessionProvider};
+use crate::annotation::Outline;
+use crate::config::{DetectionConfig, SessionConfig};
+use crate::error::{Error, Result};
+
+// the trained yolo model has a input like this, so DO NOT change this.
+const INPUT_H: u32 = 640;
+const INPUT_W: u32 = 640;
+
+// the inputs and outputs of ultralytics exports, output1 is only in segmentation models
+// older yolov5 exports name the boxes output
+const INPUTS: [Spec; 1] = [Spec::f32(&["images"], &[1, 3, 640, 640])];
+const OUTPUTS: [Spec; 2] = [
+    Spec::f32(&["output0", "output"], &[1, -1, -1]),
+    Spec::f32(&["output1"], &[1, -1, -1, -1]).optional(),
+];
+
+// the gray used by ultralytics to fill the letterbox border
+const PAD_VALUE: f32 = 114.0 / 255.0;
+
+/// A box as its corners, x1 <= x2 and y1 <= y2.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    /// Left.
+    pub x1: f32,
+    /// Top.
+    pub y1: f32,
+    /// Right.
+    pub x2: f32,
+    /// Bottom.
+    pub y2: f32,
+}
+
+impl BoundingBox {
+    /// The corners are ordered, so any two opposite corners can be given.
+    pub fn new(x1: f32, y1: f32, x2: f32, y2: f32) -> Self {
+        BoundingBox {
+            x1: x1.min(x2),
+            y1: y1.min(y2),
+            x2: x1.max(x2),
+            y2: y1.max(y2),
+        }
+    }
+
+    /// Divide by the image size.
+    pub fn normalize(self, w: f32, h: f32) -> Self {
+        let Self { x1, y1, x2, y2 } = self;
+        BoundingBox {
+            x1: x1 / w,
+            y1: y1 / h,
+            x2: x2 / w,
+            y2: y2 / h,
+        }
+    }
+
+    /// A box without width or height can not be a prompt.
+    pub fn has_area(&self) -> bool {
+        self.x1 < self.x2 && self.y1 < self.y2
+    }
+
+    fn clamp(self, max_x: f32, max_y: f32) -> Self {
+        let Self { x1, y1, x2, y2 } = self;
+        BoundingBox {
+            x1: x1.clamp(0.0, max_x),
+            y1: y1.clamp(0.0, max_y),
+            x2: x2.clamp(0.0, max_x),
+            y2: y2.clamp(0.0, max_y),
+        }
+    }
+}
+
+/// One detected object, the box is normalized to the original image.
+#[derive(Debug, Clone)]
+pub struct Detection {
+    /// Normalized to the original image.
+    pub bbox: BoundingBox,
+    /// The score of the class, times the objectness for YOLOv5.
+    pub conf: f32,
+    /// Index into the class names.
+    pub class_id: usize,
+    /// Only from segmentation models.
+    pub mask: Option<BoxMask>,
+}
+
+/// The mask of a detection, only as large as its box.
+#[derive(Debug, Clone)]
+pub struct BoxMask {
+    /// The left of the mask in the original image.
+    pub x: u32,
+    /// The top of the mask in the original image.
+    pub y: u32,
+    /// 255 inside the object, 0 outside, the size of the box.
+    pub mask: GrayImage,
+}
+
+impl BoxMask {
+    /// The outline in pixels of the original image, None if the mask is empty.
+    pub fn outline(&self) -> Option<Outline> {
+        Outline::from(&self.mask).map(|o| o.translate([self.x as f32, self.y as f32]))
+    }
+}
+
+/// How the values of the anchors are laid out in output0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    /// Yolov8 and yolov11, (1, 4 + num_classes, num_anchors).
+    AnchorsLast,
+    /// Yolov5, (1, num_anchors, 5 + num_classes), the class scores times the objectness.
+    Objectness,
+}
+
+// raw outputs of the last image
+#[derive(Debug)]
+struct RawOutput {
+    boxes: ArrayD<f32>,          // output0
+    protos: Option<ArrayD<f32>>, // output1 of segmentation models
+    letterbox: Letterbox,
+}
+
+/// Ultralytics style letterbox, the image is scaled to fit in the input with the aspect ratio kept,
+/// then centered and padded with gray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Letterbox {
+    /// Width of the original image.
+    pub ori_w: u32,
+    /// Height of the original image.
+    pub ori_h: u32,
+    /// From the original image to the model input.
+    pub scale: f32,
+    /// Width of the scaled image in the input.
+    pub new_w: u32,
+    /// Height of the scaled image in the input.
+    pub new_h: u32,
+    /// Gray columns at the left of the input.
+    pub pad_left: u32,
+    /// Gray rows at the top of the input.
+    pub pad_top: u32,
+}
+
+impl Letterbox {
+    /// The letterbox of an image of the given size.
+    pub fn new(ori_w: u32, ori_h: u32) -> Self {
+        let scale = (INPUT_W as f32 / ori_w as f32).min(INPUT_H as f32 / ori_h as f32);
+        let new_w = ((ori_w as f32 * scale).round() as u32).clamp(1, INPUT_W);
+        let new_h = ((ori_h as f32 * scale).round() as u32).clamp(1, INPUT_H);
+
+        // the same rounding as ultralytics, the extra pixel goes to right and bottom
+        let dw = (INPUT_W - new_w) as f32 / 2.0;
+        let dh = (INPUT_H - new_h) as f32 / 2.0;
+        let pad_left = (dw - 0.1).round().max(0.0) as u32;
+        let pad_top = (dh - 0.1).round().max(0.0) as u32;
+
+        Self {
+            ori_w,
+            ori_h,
+            scale,
+            new_w,
+            new_h,
+            pad_left,
+            pad_top,
+        }
+    }
+
+    /// Map a box in the model input back to the original image, normalized to [0, 1].
+    pub fn unproject(&self, bbox: BoundingBox) -> BoundingBox {
+        let BoundingBox { x1, y1, x2, y2 } = bbox;
+        let (dx, dy) = (self.pad_left as f32, self.pad_top as f32);
+        let (w, h) = (self.ori_w as f32, self.ori_h as f32);
+
+        BoundingBox::new(
+            (x1 - dx) / self.scale,
+            (y1 - dy) / self.scale,
+            (x2 - dx) / self.scale,
+            (y2 - dy) / self.scale,
+        )
+        .clamp(w, h)
+        .normalize(w, h)
+    }
+}
+
+impl From<BoundingBox> for (f32, f32, f32, f32) {
+    fn from(b: BoundingBox) -> Self {
+        (b.x1, b.y1, b.x2, b.y2)
+    }
+}
+
+impl From<&BoundingBox> for (f32, f32, f32, f32) {
+    fn from(b: &BoundingBox) -> Self {
+        (b.x1, b.y1, b.x2, b.y2)
+    }
+}
+
+impl From<BoundingBox> for [f32; 4] {
+    fn from(b: BoundingBox) -> Self {
+        [b.x1, b.y1, b.x2, b.y2]
+    }
+}
+
+impl From<&BoundingBox> for [f32; 4] {
+    fn from(b: &BoundingBox) -> Self {
+        [b.x1, b.y1, b.x2, b.y2]
+    }
+}
+
+/// An Ultralytics detection or segmentation export.
+///
+/// `forward` returns the detections above the thresholds after nms, the best first.
+#[derive(Debug)]
+pub struct YOLOmodel {
+    model: Session,
+    provider: SessionProvider,
+    class_names: Vec<String>,
+    layout: Layout,
+    signature: Signature,
+    output: String, // the name of output0
+
+    // raw output of the last image, so that thresholds can be changed without running the model
+    cache: Option<RawOutput>,
+}
+
+impl YOLOmodel {
+    /// Load `weights/yolov8s-trained.onnx` with the default session options.
+    pub fn new() -> Result<Self> {
+        Self::new_path("weights/yolov8s-trained.onnx", &SessionConfig::default())
+    }
+
+    /// Load an export, the class names are read from its metadata.
+    pub fn new_path(p: &str, config: &SessionConfig) -> Result<Self> {
+        let (model, provider) = provider::build_session(p, config)?;
+        signature::check(p, &model, &INPUTS, &OUTPUTS)?;
+        let class_names = metadata_names(&model);
+        let signature = Signature::of("YOLO", p, &model);
+        let output = signature::find(&model, &OUTPUTS[0]).unwrap_or_default();
+
+        Ok(Self {
+            model,
+            provider,
+            class_names,
+            layout: Layout::AnchorsLast,
+            signature,
+            output,
+            cache: None,
+        })
+    }
+
+    /// How the anchors are laid out in the output, AnchorsLast by default.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    fn preprocess(img: &image::DynamicImage) -> (Array<f32, Dim<[usize; 4]>>, Letterbox) {
+        let (ori_w, ori_h) = img.dimensions();
+        let letterbox = Letterbox::new(ori_w, ori_h);
+
+        let img = img.resize_exact(letterbox.new_w, letterbox.new_h, FilterType::CatmullRom);
+        let mut input = Array::from_elem((1, 3, INPUT_H as usize, INPUT_W as usize), PAD_VALUE);
+        for pixel in img.pixels() {
+            let x = (pixel.0 + letterbox.pad_left) as _;
+            let y = (pixel.1 + letterbox.pad_top) as _;
+            let [r, g, b, _] = pixel.2 .0;
+
+            input[[0, 0, y, x]] = (r as f32) / 255.0;
+            input[[0, 1, y, x]] = (g as f32) / 255.0;
+            input[[0, 2, y, x]] = (b as f32) / 255.0;
+        }
+
+        (input, letterbox)
+    }
+
+    /// Detect the objects of an image, the best first.
+    ///
+    /// The boxes are normalized to the image and have an area,
+    /// segmentation exports also give masks.
+    /// The raw output is kept for `redetect`.
+    pub fn forward(
+        &mut self,
+        img: &image::DynamicImage,
+        params: &DetectionConfig,
+    ) -> Result<Vec<Detection>> {
+        self.infer(img)?;
+        self.redetect(params)
+    }
+
+    /// Run the model on an image and keep the raw output, `redetect` postprocesses it.
+    pub fn infer(&mut self, img: &image::DynamicImage) -> Result<()> {
+        let (input, letterbox) = Self::preprocess(img);
+
+        let outputs: SessionOutputs = self.model.run(inputs!["images" => input.view()]?)?;
+        let boxes = outputs
+            .get(self.output.as_str())
+            .ok_or_else(|| Error::Inference(format!("YOLO model has no output {}", self.output)))?
+            .try_extract_tensor::<f32>()?
+            .into_owned();
+        // segmentation exports have the prototype masks as the second output
+        let protos = match outputs.get("output1") {
+            Some(protos) => Some(protos.try_extract_tensor::<f32>()?.into_owned()),
+            None => None,
+        };
+
+        self.cache = Some(RawOutput {
+            boxes,
+            protos,
+            letterbox,
+        });
+        Ok(())
+    }
+
+    /// Run the postprocess again on the output of the last image.
+    pub fn redetect(&self, params: &DetectionConfig) -> Result<Vec<Detection>> {
+        match &self.cache {
+            Some(output) => self.postprocess_output(output, params),
+            None => Err(Error::Inference(
+                "Nothing to re-detect, run Detect first".into(),
+            )),
+        }
+    }
+
+    /// Drop the output of the last image, when another image is opened.
+    pub fn clear_cache(&mut self) {
+        self.cache = None;
+    }
+
+    fn postprocess_output(
+        &self,
+        output: &RawOutput,
+        params: &DetectionConfig,
+    ) -> Result<Vec<Detection>> {
+        let protos = match &output.protos {
+            Some(protos) => Some(
+                protos
+                    .view()
+                    .index_axis_move(Axis(0), 0)
+                    .into_dimensionality::<Ix3>()
+                    .map_err(|_| Error::Inference("YOLO prototype masks are not 4D".into()))?,
+            ),
+            None => None,
+        };
+
+        Self::postprocess(
+            output.boxes.view(),
+            protos,
+            self.layout,
+            &output.letterbox,
+            params,
+        )
+    }
+
+    // output is (1, 4 + num_classes + num_protos, num_anchors) in the model input space,
+    // or with the objectness and the anchors first for Layout::Objectness,
+    // the last num_protos values of an anchor are the coefficients of its mask,
+    // protos is (num_protos, mask_h, mask_w) for segmentation models
+    fn postprocess(
+        output: ArrayViewD<f32>,
+        protos: Option<ArrayView3<f32>>,
+        layout: Layout,
+        letterbox: &Letterbox,
+        params: &DetectionConfig,
+    ) -> Result<Vec<Detection>> {
+        if output.ndim() != 3 {
+            return Err(Error::Inference(format!(
+                "unexpected YOLO output shape: {:?}",
+                output.shape()
+            )));
+        }
+        // one row for each anchor
+        let output = match layout {
+            Layout::AnchorsLast => output.t().into_owned(),
+            Layout::Objectness => output.permuted_axes(&[1, 2, 0][..]).into_owned(),
+        };
+        let num_protos = protos.map_or(0, |p| p.shape()[0]);
+        let first_score = match layout {
+            Layout::AnchorsLast => 4,
+            Layout::Objectness => 5,
+        };
+
+        let mut boxes = Vec::new();
+        let mut coefficients = Vec::new();
+        for row in output.axis_iter(Axis(0)) {
+            let row: Vec<_> = row.iter().copied().collect();
+            if row.len() < first_score + num_protos {
+                return Err(Error::Inference(format!(
+                    "YOLO output has {} values per anchor, expected more than {}",
+                    row.len(),
+                    first_score + num_protos
+                )));
+            }
+            let scores = &row[first_score..row.len() - num_protos];
+            let (class_id, prob) = scores
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (index, *value))
+                .reduce(|accum, row| if row.1 > accum.1 { row } else { accum })
+                .ok_or_else(|| Error::Inference("YOLO output has no class scores".into()))?;
+            let prob = match layout {
+                Layout::AnchorsLast => prob,
+                Layout::Objectness => prob * row[4],
+            };
+
+            if prob < params.conf_threshold {
+                continue;
+            }
+
+            let xc = row[0];
+            let yc = row[1];
+            let w = row[2];
+            let h = row[3];
+            // boxes in the padding are flat once clamped to the image
+            let bbox = letterbox.unproject(BoundingBox::new(
+                xc - w / 2.0,
+                yc - h / 2.0,
+                xc + w / 2.0,
+                yc + h / 2.0,
+            ));
+            if !bbox.has_area() {
+                continue;
+            }
+            boxes.push(Detection {
+                bbox,
+                conf: prob,
+                class_id,
+                mask: None,
+            });
+            coefficients.push(row[row.len() - num_protos..].to_vec());
+        }
+
+        // masks are only decoded for the kept boxes
+        let kept = Self::nms(&boxes, params);
+        let mut result = Vec::with_capacity(kept.len());
+        for i in kept {
+            let mut detection = boxes[i].clone();
+            if let Some(protos) = protos {
+                detection.mask = Some(Self::decode_mask(
+                    &coefficients[i],
+                    protos,
+                    &detection.bbox,
+                    letterbox,
+                ));
+            }
+            result.push(detection);
+        }
+
+        Ok(result)
+    }
+
+    // the coefficients times the prototypes, upsampled to the original image
+    // and cropped to the box, the same as ultralytics,
+    // only the prototypes under the box are combined and only the box is upsampled
+    fn decode_mask(
+        coefficients: &[f32],
+        protos: ArrayView3<f32>,
+        bbox: &BoundingBox,
+        letterbox: &Letterbox,
+    ) -> BoxMask {
+        let (mask_h, mask_w) = (protos.shape()[1], protos.shape()[2]);
+        let (ori_w, ori_h) = (letterbox.ori_w, letterbox.ori_h);
+
+        // the pixels of the original image the box touches
+        let (w, h) = (ori_w as f32, ori_h as f32);
+        let (x1, y1, x2, y2) = (bbox.x1 * w, bbox.y1 * h, bbox.x2 * w, bbox.y2 * h);
+        let x0 = (x1.floor().max(0.0) as u32).min(ori_w - 1);
+        let y0 = (y1.floor().max(0.0) as u32).min(ori_h - 1);
+        let x_end = (x2.ceil() as u32).clamp(x0 + 1, ori_w);
+        let y_end = (y2.ceil() as u32).clamp(y0 + 1, ori_h);
+
+        // where the center of a pixel of the original image is in the prototypes
+        let sx = mask_w as f32 / INPUT_W as f32;
+        let sy = mask_h as f32 / INPUT_H as f32;
+        let proto_x =
+            |x: u32| ((x as f32 + 0.5) * letterbox.scale + letterbox.pad_left as f32) * sx - 0.5;
+        let proto_y =
+            |y: u32| ((y as f32 + 0.5) * letterbox.scale + letterbox.pad_top as f32) * sy - 0.5;
+
+        // the prototype cells the box interpolates between
+        let px0 = (proto_x(x0).floor().max(0.0) as usize).min(mask_w - 1);
+        let py0 = (proto_y(y0).floor().max(0.0) as usize).min(mask_h - 1);
+        let px1 = (proto_x(x_end - 1).floor().max(0.0) as usize + 1).clamp(px0, mask_w - 1);
+        let py1 = (proto_y(y_end - 1).floor().max(0.0) as usize + 1).clamp(py0, mask_h - 1);
+        let window = protos.slice(s![.., py0..=py1, px0..=px1]);
+        let mut logits = Array2::<f32>::zeros((py1 - py0 + 1, px1 - px0 + 1));
+        for (c, proto) in coefficients.iter().zip(window.outer_iter()) {
+            logits.scaled_add(*c, &proto);
+        }
+
+        // a logit above 0 is a probability above 0.5
+        let mask = GrayImage::from_fn(x_end - x0, y_end - y0, |x, y| {
+            let (x, y) = (x0 + x, y0 + y);
+            let (cx, cy) = (x as f32 + 0.5, y as f32 + 0.5);
+            let inside = cx >= x1 && cx <= x2 && cy >= y1 && cy <= y2;
+            let logit = bilinear(&logits, proto_x(x) - px0 as f32, proto_y(y) - py0 as f32);
+            image::Luma([if inside && logit > 0.0 { 255 } else { 0 }])
+        });
+
+        BoxMask { x: x0, y: y0, mask }
+    }
+
+    // greedy nms, a box is only suppressed by a higher scored box of the same class
+    // returns the indices of the kept boxes, by descending confidence
+    fn nms(boxes: &[Detection], params: &DetectionConfig) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..boxes.len()).collect();
+        order.sort_by(|&i, &j| boxes[j].conf.total_cmp(&boxes[i].conf));
+
+        let mut result: Vec<usize> = Vec::new();
+        for i in order {
+            if params.max_detections > 0 && result.len() >= params.max_detections {
+                break;
+            }
+
+            let candidate = &boxes[i];
+            let suppressed = result.iter().any(|&k| {
+                boxes[k].class_id == candidate.class_id
+                    && Self::iou(&boxes[k].bbox, &candidate.bbox) >= params.iou_threshold
+            });
+            if !suppressed {
+                result.push(i);
+            }
+        }
+
+        result
+    }
+
+    fn iou(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+        let union = Self::union(box1, box2);
+        if union <= 0.0 {
+            return 0.0;
+        }
+        Self::intersection(box1, box2) / union
+    }
+
+    fn intersection(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+        (box1.x2.min(box2.x2) - box1.x1.max(box2.x1)).max(0.0)
+            * (box1.y2.min(box2.y2) - box1.y1.max(box2.y1)).max(0.0)
+    }
+
+    fn union(box1: &BoundingBox, box2: &BoundingBox) -> f32 {
+        (box1.x2 - box1.x1) * (box1.y2 - box1.y1) + (box2.x2 - box2.x1) * (box2.y2 - box2.y1)
+            - Self::intersection(box1, box2)
+    }
+}
+
+impl Detector for YOLOmodel {
+    fn providers(&self) -> Vec<(&'static str, SessionProvider)> {
+        vec![("YOLO", self.provider.clone())]
+    }
+
+    fn signatures(&self) -> Vec<Signature> {
+        vec![self.signature.clone()]
+    }
+
+    fn class_names(&self) -> &[String] {
+        &self.class_names
+    }
+
+    fn infer(&mut self, img: &image::DynamicImage) -> Result<()> {
+        YOLOmodel::infer(self, img)
+    }
+
+    fn redetect(&self, params: &DetectionConfig) -> Result<Vec<Detection>> {
+        YOLOmodel::redetect(self, params)
+    }
+
+    fn clear_cache(&mut self) {
+        YOLOmodel::clear_cache(self)
+    }
+}
+
+// the value at (x, y) between the cells, clamped to the edges
+fn bilinear(values: &Array2<f32>, x: f32, y: f32) -> f32 {
+    let (h, w) = values.dim();
+    let x = x.clamp(0.0, (w - 1) as f32);
+    let y = y.clamp(0.0, (h - 1) as f32);
+    let (i, j) = (y.floor() as usize, x.floor() as usize);
+    let (i1, j1) = ((i + 1).min(h - 1), (j + 1).min(w - 1));
+    let (fy, fx) = (y - i as f32, x - j as f32);
+
+    values[[i, j]] * (1.0 - fx) * (1.0 - fy)
+        + values[[i, j1]] * fx * (1.0 - fy)
+        + values[[i1, j]] * (1.0 - fx) * fy
+        + values[[i1, j1]] * fx * fy
+}
+
+// ultralytics exports store the class names in the metadata,
+// a model without them gets an empty list
+pub(super) fn metadata_names(model: &Session) -> Vec<String> {
+    model
+        .metadata()
+        .and_then(|meta| meta.custom("names"))
+        .ok()
+        .flatten()
+        .map(|names| parse_names(&names))
+        .unwrap_or_default()
+}
+
+// parse the `names` metadata of ultralytics, which is a python dict like "{0: 'person', 1: 'car'}"
+fn parse_names(names: &str) -> Vec<String> {
+    let mut result: Vec<(usize, String)> = Vec::new();
+    let mut chars = names.chars().peekable();
+
+    while chars.peek().is_some() {
+        // the key
+        let key: String = chars
+            .by_ref()
+            .skip_while(|c| !c.is_ascii_digit())
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        let Ok(key) = key.parse::<usize>() else {
+            break;
+        };
+
+        // the quoted value
+        let Some(quote) = chars.by_ref().find(|c| *c == '\'' || *c == '"') else {
+            break;
+        };
+        let value: String = chars.by_ref().take_while(|c| *c != quote).collect();
+        result.push((key, value));
+    }
+
+    result.sort_by_key(|(key, _)| *key);
+    result.into_iter().map(|(_, value)| value).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage};
+    use ndarray::Array3;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    #[test]
+    fn letterbox_wide_image() {
+        let lb = Letterbox::new(1280, 640);
+        assert_close(lb.scale, 0.5);
+        assert_eq!((lb.new_w, lb.new_h), (640, 320));
+        assert_eq!((lb.pad_left, lb.pad_top), (0, 160));
+    }
+
+    #[test]
+    fn letterbox_tall_image() {
+        let lb = Letterbox::new(300, 600);
+        assert_eq!((lb.new_w, lb.new_h), (320, 640));
+        assert_eq!((lb.pad_left, lb.pad_top), (160, 0));
+    }
+
+    #[test]
+    fn preprocess_pads_with_gray() {
+        // a white 200x100 image, only the middle band of the input should be white
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(200, 100, Rgb([255, 255, 255])));
+        let (input, lb) = YOLOmodel::preprocess(&img);
+
+        assert_eq!(input.shape(), &[1, 3, 640, 640]);
+        assert_eq!((lb.pad_left, lb.pad_top), (0, 160));
+        assert_close(input[[0, 0, 0, 320]], PAD_VALUE);
+        assert_close(input[[0, 0, 159, 320]], PAD_VALUE);
+        assert_close(input[[0, 0, 160, 320]], 1.0);
+        assert_close(input[[0, 0, 479, 320]], 1.0);
+        assert_close(input[[0, 0, 480, 320]], PAD_VALUE);
+    }
+
+    #[test]
+    fn unproject_non_square() {
+        // 1280x640 -> scale 0.5, pad top 160
+        let lb = Letterbox::new(1280, 640);
+        let bbox = lb.unproject(BoundingBox::new(64.0, 192.0, 320.0, 320.0));
+
+        assert_close(bbox.x1, 128.0 / 1280.0);
+        assert_close(bbox.y1, 64.0 / 640.0);
+        assert_close(bbox.x2, 640.0 / 1280.0);
+        assert_close(bbox.y2, 320.0 / 640.0);
+    }
+
+    #[test]
+    fn unproject_clamps_into_padding() {
+        let lb = Letterbox::new(1280, 640);
+        let bbox = lb.unproject(BoundingBox::new(0.0, 100.0, 640.0, 600.0));
+
+        assert_close(bbox.y1, 0.0);
+        assert_close(bbox.y2, 1.0);
+    }
+
+    #[test]
+    fn postprocess_drops_boxes_in_the_padding() {
+        // 1280x640 -> pad top 160, the first box is above the image, the second has no width
+        let lb = Letterbox::new(1280, 640);
+        let mut output = Array3::<f32>::zeros((1, 5, 2));
+        for (i, values) in [
+            [320.0, 60.0, 128.0, 64.0, 0.9],
+            [320.0, 400.0, 0.0, 64.0, 0.9],
+        ]
+        .iter()
+        .enumerate()
+        {
+            for (j, v) in values.iter().enumerate() {
+                output[[0, j, i]] = *v;
+            }
+        }
+
+        let params = DetectionConfig::default();
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            None,
+            Layout::AnchorsLast,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert!(boxes.is_empty());
+    }
+
+    #[test]
+    fn postprocess_maps_back_to_original() {
+        // one anchor with a single class, centered in the lower half of a 1280x640 image
+        let lb = Letterbox::new(1280, 640);
+        let mut output = Array3::<f32>::zeros((1, 5, 2));
+        output[[0, 0, 0]] = 320.0; // xc
+        output[[0, 1, 0]] = 400.0; // yc
+        output[[0, 2, 0]] = 128.0; // w
+        output[[0, 3, 0]] = 64.0; // h
+        output[[0, 4, 0]] = 0.9; // score
+        output[[0, 4, 1]] = 0.1; // the other anchor is below threshold
+
+        let params = DetectionConfig::default();
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            None,
+            Layout::AnchorsLast,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert_eq!(boxes.len(), 1);
+
+        let Detection {
+            bbox,
+            conf,
+            class_id,
+            mask,
+        } = boxes[0].clone();
+        assert!(mask.is_none());
+        assert_close(conf, 0.9);
+        assert_eq!(class_id, 0);
+        assert_close(bbox.x1, 512.0 / 1280.0);
+        assert_close(bbox.x2, 768.0 / 1280.0);
+        assert_close(bbox.y1, 416.0 / 640.0);
+        assert_close(bbox.y2, 544.0 / 640.0);
+    }
+
+    fn detection(bbox: [f32; 4], conf: f32, class_id: usize) -> Detection {
+        Detection {
+            bbox: BoundingBox::new(bbox[0], bbox[1], bbox[2], bbox[3]),
+            conf,
+            class_id,
+            mask: None,
+        }
+    }
+
+    #[test]
+    fn nms_is_class_aware() {
+        let params = DetectionConfig::default();
+        let boxes = vec![
+            detection([0.0, 0.0, 10.0, 10.0], 0.9, 0),
+            detection([0.0, 0.0, 10.0, 9.0], 0.8, 0), // overlaps the first one, same class
+            detection([0.0, 0.0, 10.0, 9.5], 0.7, 1), // overlaps the first one, other class
+            detection([20.0, 20.0, 30.0, 30.0], 0.6, 0),
+        ];
+
+        let kept: Vec<f32> = YOLOmodel::nms(&boxes, &params)
+            .iter()
+            .map(|&i| boxes[i].conf)
+            .collect();
+        assert_eq!(kept, vec![0.9, 0.7, 0.6]);
+    }
+
+    #[test]
+    fn nms_respects_thresholds() {
+        let boxes = vec![
+            detection([0.0, 0.0, 10.0, 10.0], 0.9, 0),
+            detection([0.0, 0.0, 10.0, 6.0], 0.8, 0), // iou 0.6
+            detection([20.0, 20.0, 30.0, 30.0], 0.6, 0),
+        ];
+
+        let params = DetectionConfig {
+            iou_threshold: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(YOLOmodel::nms(&boxes, &params).len(), 2);
+
+        let params = DetectionConfig {
+            iou_threshold: 0.7,
+            max_detections: 2,
+            ..Default::default()
+        };
+        let kept = YOLOmodel::nms(&boxes, &params);
+        assert_eq!(kept.len(), 2);
+        assert_close(boxes[kept[1]].conf, 0.8);
+    }
+
+    #[test]
+    fn postprocess_conf_threshold() {
+        let lb = Letterbox::new(640, 640);
+        let mut output = Array3::<f32>::zeros((1, 5, 1));
+        output[[0, 2, 0]] = 10.0;
+        output[[0, 3, 0]] = 10.0;
+        output[[0, 4, 0]] = 0.4;
+
+        let params = DetectionConfig::default();
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            None,
+            Layout::AnchorsLast,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert!(boxes.is_empty());
+
+        let params = DetectionConfig {
+            conf_threshold: 0.3,
+            ..Default::default()
+        };
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            None,
+            Layout::AnchorsLast,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert_eq!(boxes.len(), 1);
+    }
+
+    #[test]
+    fn postprocess_yolov5_objectness() {
+        // two anchors first, then x, y, w, h, objectness and two classes
+        let lb = Letterbox::new(640, 640);
+        let mut output = Array3::<f32>::zeros((1, 2, 7));
+        for (i, values) in [
+            [100.0, 100.0, 20.0, 20.0, 0.9, 0.1, 0.8],
+            [300.0, 300.0, 20.0, 20.0, 0.5, 0.9, 0.1], // 0.45 after the objectness
+        ]
+        .iter()
+        .enumerate()
+        {
+            for (j, v) in values.iter().enumerate() {
+                output[[0, i, j]] = *v;
+            }
+        }
+
+        let params = DetectionConfig::default();
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            None,
+            Layout::Objectness,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].class_id, 1);
+        assert_close(boxes[0].conf, 0.72);
+        assert_close(boxes[0].bbox.x1, 90.0 / 640.0);
+    }
+
+    #[test]
+    fn postprocess_decodes_masks() {
+        // two classes and two prototypes of 4x4 for a 640x640 image,
+        // the first prototype is the left half, the second one the right half
+        let lb = Letterbox::new(640, 640);
+        let mut protos = Array3::<f32>::from_elem((2, 4, 4), -10.0);
+        protos.slice_mut(ndarray::s![0, .., ..2]).fill(10.0);
+        protos.slice_mut(ndarray::s![1, .., 2..]).fill(10.0);
+
+        // a box over the whole image, class 1, using the first prototype
+        let mut output = Array3::<f32>::zeros((1, 4 + 2 + 2, 1));
+        output[[0, 0, 0]] = 320.0;
+        output[[0, 1, 0]] = 320.0;
+        output[[0, 2, 0]] = 640.0;
+        output[[0, 3, 0]] = 640.0;
+        output[[0, 5, 0]] = 0.9;
+        output[[0, 6, 0]] = 1.0;
+
+        let params = DetectionConfig::default();
+        let boxes = YOLOmodel::postprocess(
+            output.view().into_dyn(),
+            Some(protos.view()),
+            Layout::AnchorsLast,
+            &lb,
+            &params,
+        )
+        .unwrap();
+        assert_eq!(boxes.len(), 1);
+        // the coefficients are not class scores
+        assert_eq!(boxes[0].class_id, 1);
+
+        let mask = boxes[0].mask.as_ref().unwrap();
+        assert_eq!((mask.x, mask.y), (0, 0));
+        assert_eq!(mask.mask.dimensions(), (640, 640));
+        assert_eq!(mask.mask.get_pixel(10, 320).0[0], 255);
+        assert_eq!(mask.mask.get_pixel(630, 320).0[0], 0);
+    }
+
+    #[test]
+    fn masks_are_cropped_to_the_box() {
+        // a prototype that covers everything, the box is the top left quarter
+        let lb = Letterbox::new(640, 640);
+        let protos = Array3::<f32>::from_elem((1, 4, 4), 10.0);
+        let bbox = BoundingBox::new(0.0, 0.0, 0.5, 0.5);
+
+        let mask = YOLOmodel::decode_mask(&[1.0], protos.view(), &bbox, &lb);
+        assert_eq!((mask.x, mask.y), (0, 0));
+        assert_eq!(mask.mask.dimensions(), (320, 320));
+        assert!(mask.mask.pixels().all(|p| p.0[0] == 255));
+    }
+
+    #[test]
+    fn masks_are_box_sized_with_their_offset() {
+        // a wide image, the prototype is on in its right half,
+        // the box is in the middle of the image
+        let lb = Letterbox::new(1280, 640);
+        let mut protos = Array3::<f32>::from_elem((1, 8, 8), -10.0);
+        protos.slice_mut(ndarray::s![0, .., 4..]).fill(10.0);
+        let bbox = BoundingBox::new(0.25, 0.5, 0.75, 0.75);
+
+        let mask = YOLOmodel::decode_mask(&[1.0], protos.view(), &bbox, &lb);
+        assert_eq!((mask.x, mask.y), (320, 320));
+        assert_eq!(mask.mask.dimensions(), (640, 160));
+        assert_eq!(mask.mask.get_pixel(10, 80).0[0], 0);
+        assert_eq!(mask.mask.get_pixel(630, 80).0[0], 255);
+
+        // the outline is in the original image
+        let outline = mask.outline().unwrap();
+        assert!(outline
+            .points()
+            .iter()
+            .all(|p| (600.0..=960.0).contains(&p[0]) && (320.0..=480.0).contains(&p[1])));
+    }
+
+    #[test]
+    fn parse_ultralytics_names() {
+        let names = parse_names("{0: 'person', 1: \"traffic light\", 2: 'hair drier'}");
+        assert_eq!(names, vec!["person", "traffic light", "hair drier"]);
+
+        assert!(parse_names("").is_empty());
+        assert!(parse_names("{}").is_empty());
+    }
+}
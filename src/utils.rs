@@ -4,6 +4,27 @@ use imageproc::{
     contours::{find_contours, Contour},
     distance_transform::Norm,
 };
+use std::path::{Path, PathBuf};
+
+/// The jpg, jpeg and png files in a folder and its subfolders, sorted by name.
+///
+/// Entries that cannot be read are skipped, a single image gives itself.
+pub fn image_paths(folder: &Path) -> Vec<PathBuf> {
+    walkdir::WalkDir::new(folder)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_file()
+                && entry.path().extension().is_some_and(|ext| {
+                    ["jpg", "jpeg", "png"]
+                        .iter()
+                        .any(|e| ext.eq_ignore_ascii_case(e))
+                })
+        })
+        .map(|entry| entry.into_path())
+        .collect()
+}
 
 #[allow(dead_code)]
 pub fn mask_or(masks: Vec<image::GrayImage>) -> image::GrayImage {
@@ -17,13 +38,14 @@ pub fn mask_or(masks: Vec<image::GrayImage>) -> image::GrayImage {
     result
 }
 
-pub fn extract_outline(mask: &GrayImage) -> Vec<[f32; 2]> {
+// returns None if there is nothing left in the mask
+pub fn extract_outline(mask: &GrayImage) -> Option<Vec<[f32; 2]>> {
     // morphological opening, it can remove small object and noise
     // it's not the same as erosion
     let mask = imageproc::morphology::open(mask, Norm::LInf, 3);
 
     let contours: Vec<Contour<i32>> = find_contours(&mask); // find all contours
-    let contour = contours.into_iter().max_by_key(|c| c.points.len()).unwrap(); // keep the longest one
+    let contour = contours.into_iter().max_by_key(|c| c.points.len())?; // keep the longest one
 
     const EPSILON: f64 = 2.0;
 
@@ -37,8 +59,10 @@ pub fn extract_outline(mask: &GrayImage) -> Vec<[f32; 2]> {
     // the simplification function uses the Ramer-Douglas-Peucker algorithm
     let simplified_line = line_string.simplify(&EPSILON);
 
-    simplified_line // return to Vec<[f32;2]>
+    let outline = simplified_line // return to Vec<[f32;2]>
         .coords()
         .map(|p| [p.x as f32, p.y as f32])
-        .collect()
+        .collect();
+
+    Some(outline)
 }